@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use utoipa::ToSchema;
+
+use crate::file_handler;
+
+/// Firmware every device of a hardware model should run, device asking for update gets it
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub struct FirmwareAssignment {
+    pub hardware_model: String,
+    pub firmware_version: String,
+    /// Where the device image firmware binary is downloaded from
+    pub url: String,
+    /// Only for registered devices in this group, wins over the assignment without group
+    pub group: Option<String>,
+}
+
+impl FirmwareAssignment {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.hardware_model.is_empty() {
+            return Err(String::from("hardware_model must not be empty"));
+        }
+        if self.firmware_version.is_empty() {
+            return Err(String::from("firmware_version must not be empty"));
+        }
+        file_handler::validate_url(&self.url)
+    }
+}
+
+/// Hardware model and optional group an assignment is for
+type AssignmentKey = (String, Option<String>);
+
+/// Assigned firmware by hardware model and group, shared between http server and jobs thread
+#[derive(Debug, Clone, Default)]
+pub struct Assignments {
+    assignments: Arc<RwLock<HashMap<AssignmentKey, FirmwareAssignment>>>,
+}
+
+impl Assignments {
+    /// Add or replace the assignment of a model and group, return true when it is new
+    pub fn upsert(&self, assignment: FirmwareAssignment) -> bool {
+        let key = (assignment.hardware_model.clone(), assignment.group.clone());
+        let mut assignments = self.assignments.write().unwrap();
+        assignments.insert(key, assignment).is_none()
+    }
+
+    /// Every assignment sorted by model then group
+    pub fn list(&self) -> Vec<FirmwareAssignment> {
+        let assignments = self.assignments.read().unwrap();
+        let mut list: Vec<FirmwareAssignment> = assignments.values().cloned().collect();
+        list.sort_by(|a, b| (&a.hardware_model, &a.group).cmp(&(&b.hardware_model, &b.group)));
+        list
+    }
+
+    /// Assignment for a device of this model in one of these groups, or the one of the whole model
+    pub fn find(&self, hardware_model: &str, groups: &[String]) -> Option<FirmwareAssignment> {
+        let assignments = self.assignments.read().unwrap();
+        groups
+            .iter()
+            .find_map(|group| assignments.get(&(hardware_model.to_string(), Some(group.clone()))))
+            .or_else(|| assignments.get(&(hardware_model.to_string(), None)))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assignment(version: &str, group: Option<&str>) -> FirmwareAssignment {
+        FirmwareAssignment {
+            hardware_model: String::from("esp32"),
+            firmware_version: String::from(version),
+            url: format!("http://a/{version}.bin"),
+            group: group.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_find_prefers_group() {
+        let assignments = Assignments::default();
+        assert!(assignments.upsert(assignment("1.0.0", None)));
+        assert!(assignments.upsert(assignment("1.1.0-beta", Some("lab"))));
+        assert!(!assignments.upsert(assignment("1.0.1", None)));
+
+        let version = |groups: &[&str]| {
+            let groups: Vec<String> = groups.iter().map(|g| g.to_string()).collect();
+            assignments
+                .find("esp32", &groups)
+                .map(|assignment| assignment.firmware_version)
+        };
+        assert_eq!(version(&[]).as_deref(), Some("1.0.1"));
+        assert_eq!(version(&["field", "lab"]).as_deref(), Some("1.1.0-beta"));
+        assert_eq!(assignments.find("nrf52", &[]), None);
+        assert_eq!(assignments.list().len(), 2);
+    }
+}
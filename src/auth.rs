@@ -0,0 +1,218 @@
+use axum::extract::{Request, State};
+use axum::http::{header, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+
+use crate::httpserver::ApiError;
+use crate::settings::Settings;
+
+/// Role given to an api token, operator can do everything read only can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    ReadOnly,
+    Operator,
+}
+
+impl Role {
+    /// Role needed to call the given method, anything that is not a read is an operator action
+    fn required_for(method: &Method) -> Self {
+        match *method {
+            Method::GET | Method::HEAD => Self::ReadOnly,
+            _ => Self::Operator,
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read_only" => Ok(Self::ReadOnly),
+            "operator" => Ok(Self::Operator),
+            other => Err(format!("unknown role {other}")),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ApiToken {
+    pub token: String,
+    pub role: Role,
+}
+
+// Settings are logged with `{:?}`, the token itself must never show up
+impl fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiToken")
+            .field("token", &"<redacted>")
+            .field("role", &self.role)
+            .finish()
+    }
+}
+
+/// Known api tokens, only the sha256 of each token is kept in memory.
+/// Default one has no token and rejects every request.
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    keys: Arc<HashMap<Vec<u8>, Role>>,
+    disabled: bool,
+}
+
+impl ApiKeys {
+    pub fn new(tokens: &[ApiToken]) -> Self {
+        let keys = tokens
+            .iter()
+            .map(|t| (Self::digest(&t.token), t.role))
+            .collect();
+        Self {
+            keys: Arc::new(keys),
+            disabled: false,
+        }
+    }
+
+    /// Let every request through without any token
+    pub fn disabled() -> Self {
+        Self {
+            disabled: true,
+            ..Default::default()
+        }
+    }
+
+    /// Collect tokens from `api_tokens` and `api_token_file`.
+    /// Token file has one `<role> <token>` per line, blank lines and `#` comments are ignored.
+    /// Without any token it is an error, unless `http_auth_disabled` is set.
+    pub fn from_settings(settings: &Settings) -> Result<Self, Box<dyn Error>> {
+        if settings.http_auth_disabled {
+            return Ok(Self::disabled());
+        }
+        let mut tokens = settings.api_tokens.clone();
+        if let Some(path) = &settings.api_token_file {
+            for (no, line) in fs::read_to_string(path)?.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let Some((role, token)) = line.split_once(char::is_whitespace) else {
+                    return Err(format!("{path}:{} expected `<role> <token>`", no + 1).into());
+                };
+                tokens.push(ApiToken {
+                    token: token.trim().to_string(),
+                    role: role
+                        .parse()
+                        .map_err(|err| format!("{path}:{} {err}", no + 1))?,
+                });
+            }
+        }
+        if tokens.is_empty() {
+            return Err(
+                "no api token configured, set http_auth_disabled = true to serve the api without authentication".into(),
+            );
+        }
+        Ok(Self::new(&tokens))
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    fn role_of(&self, token: &str) -> Option<Role> {
+        self.keys.get(&Self::digest(token)).copied()
+    }
+
+    fn digest(token: &str) -> Vec<u8> {
+        Sha256::digest(token.as_bytes()).to_vec()
+    }
+}
+
+/// Middleware checking `Authorization: Bearer <token>` of every request against its required role
+pub async fn authorize(State(keys): State<ApiKeys>, request: Request, next: Next) -> Response {
+    if keys.is_disabled() {
+        return next.run(request).await;
+    }
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    let Some(role) = token.and_then(|token| keys.role_of(token.trim())) else {
+        warn!(target: "audit", "{method} {path} rejected, missing or unknown token");
+        let mut response =
+            ApiError::new(StatusCode::UNAUTHORIZED, "Missing or invalid token").into_response();
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+        return response;
+    };
+
+    let required = Role::required_for(&method);
+    if role < required {
+        warn!(target: "audit", "{method} {path} forbidden for {role:?} token, needs {required:?}");
+        return ApiError::new(StatusCode::FORBIDDEN, "Token is not allowed to do this")
+            .into_response();
+    }
+
+    info!(target: "audit", "{method} {path} by {role:?} token");
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_role() {
+        assert_eq!(Role::required_for(&Method::GET), Role::ReadOnly);
+        assert_eq!(Role::required_for(&Method::POST), Role::Operator);
+        assert!(Role::Operator > Role::ReadOnly);
+    }
+
+    #[test]
+    fn test_role_of_token() {
+        let keys = ApiKeys::new(&[ApiToken {
+            token: String::from("secret"),
+            role: Role::Operator,
+        }]);
+
+        assert!(!keys.is_disabled());
+        assert_eq!(keys.role_of("secret"), Some(Role::Operator));
+        assert_eq!(keys.role_of("secret2"), None);
+        assert!(!ApiKeys::default().is_disabled());
+        assert!(ApiKeys::disabled().is_disabled());
+    }
+
+    #[test]
+    fn test_no_token_is_refused() {
+        let mut settings = Settings::load(&Default::default()).unwrap();
+        settings.api_tokens.clear();
+        settings.api_token_file = None;
+        settings.http_auth_disabled = false;
+        assert!(ApiKeys::from_settings(&settings).is_err());
+
+        settings.http_auth_disabled = true;
+        assert!(ApiKeys::from_settings(&settings).unwrap().is_disabled());
+    }
+
+    #[test]
+    fn test_token_is_redacted() {
+        let token = ApiToken {
+            token: String::from("secret"),
+            role: Role::ReadOnly,
+        };
+        let debug = format!("{token:?}");
+        assert!(!debug.contains("secret"));
+        assert!(debug.contains("ReadOnly"));
+    }
+}
@@ -17,3 +17,45 @@ impl fmt::Display for CustomError {
 }
 
 impl Error for CustomError {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TelemetryError {
+    Malformed(String),
+    UnknownCommand(u8),
+    UnexpectedTopic(String),
+}
+
+impl fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Malformed(msg) => write!(f, "Malformed payload ({})", msg),
+            Self::UnknownCommand(value) => write!(f, "Unknown command type {value:#04x}"),
+            Self::UnexpectedTopic(topic) => write!(f, "Unexpected topic {topic}"),
+        }
+    }
+}
+
+impl Error for TelemetryError {}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Load(config::ConfigError),
+    Invalid { key: &'static str, reason: String },
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Load(err) => write!(f, "Load settings failed ({err})"),
+            Self::Invalid { key, reason } => write!(f, "Invalid setting `{key}` ({reason})"),
+        }
+    }
+}
+
+impl Error for SettingsError {}
+
+impl From<config::ConfigError> for SettingsError {
+    fn from(err: config::ConfigError) -> Self {
+        Self::Load(err)
+    }
+}
@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use utoipa::ToSchema;
+
+use crate::topic;
+
+/// Device as it is registered through the http api
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub struct Device {
+    pub device_id: String,
+    /// Firmware image is only sent when it is built for this model
+    pub hardware_model: Option<String>,
+    /// Updated when a job that carries `firmware_version` succeeds
+    pub firmware_version: Option<String>,
+    /// Tags to target several devices with one request
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Override `chunk_size_per_transmission` for this device
+    #[schema(minimum = 1)]
+    pub chunk_size: Option<u16>,
+    /// Free form protocol capabilities reported by the device, e.g. `mqtt_v5`
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl Device {
+    pub fn validate(&self) -> Result<(), String> {
+        topic::validate_level(&self.device_id).map_err(|reason| format!("device_id {reason}"))?;
+        if self.chunk_size == Some(0) {
+            return Err(String::from("chunk_size must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+/// Known devices, shared between http server and jobs thread
+#[derive(Debug, Clone, Default)]
+pub struct DeviceRegistry {
+    devices: Arc<RwLock<HashMap<String, Device>>>,
+}
+
+impl DeviceRegistry {
+    /// Add or replace a device, return true when it wasn't registered before
+    pub fn upsert(&self, device: Device) -> bool {
+        let mut devices = self.devices.write().unwrap();
+        devices.insert(device.device_id.clone(), device).is_none()
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<Device> {
+        self.devices.read().unwrap().get(device_id).cloned()
+    }
+
+    /// Every device sorted by id, only the ones in `group` when it is given
+    pub fn list(&self, group: Option<&str>) -> Vec<Device> {
+        let devices = self.devices.read().unwrap();
+        let mut list: Vec<Device> = devices
+            .values()
+            .filter(|device| group.is_none_or(|group| device.groups.iter().any(|g| g == group)))
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        list
+    }
+
+    pub fn set_firmware_version(&self, device_id: &str, version: &str) {
+        if let Some(device) = self.devices.write().unwrap().get_mut(device_id) {
+            device.firmware_version = Some(version.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(device_id: &str, groups: &[&str]) -> Device {
+        Device {
+            device_id: String::from(device_id),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_list_by_group() {
+        let registry = DeviceRegistry::default();
+        assert!(registry.upsert(device("b", &["lab"])));
+        assert!(registry.upsert(device("a", &["lab", "field"])));
+        assert!(registry.upsert(device("c", &[])));
+        assert!(!registry.upsert(device("c", &["field"])));
+
+        let ids = |list: Vec<Device>| list.into_iter().map(|d| d.device_id).collect::<Vec<_>>();
+        assert_eq!(ids(registry.list(None)), ["a", "b", "c"]);
+        assert_eq!(ids(registry.list(Some("lab"))), ["a", "b"]);
+        assert_eq!(ids(registry.list(Some("field"))), ["a", "c"]);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(device("a", &[]).validate().is_ok());
+        assert!(device("", &[]).validate().is_err());
+        let device = Device {
+            chunk_size: Some(0),
+            ..device("a", &[])
+        };
+        assert!(device.validate().is_err());
+    }
+}
@@ -2,8 +2,10 @@ use bytes::Bytes;
 use reqwest::StatusCode;
 use sha2::{Digest, Sha256};
 use std::error::Error;
+use std::time::Instant;
 
 use crate::custom_error::CustomError;
+use crate::metrics::metrics;
 use crate::settings::settings;
 
 #[derive(Debug, Default)]
@@ -12,13 +14,14 @@ pub struct BinaryData {
     pub hash: Vec<u8>,
     pub current_chunk_id: u16,
     pub last_bytes_index: u16,
+    pub chunk_size: u16,
 }
 
 impl Iterator for BinaryData {
     type Item = Bytes;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let chunk_size = settings().chunk_size_per_transmission;
+        let chunk_size = self.chunk_size;
         if self.last_bytes_index >= self.data.len() as u16 {
             debug!("No more data of the image");
             return None;
@@ -45,21 +48,45 @@ impl Iterator for BinaryData {
     }
 }
 
+impl BinaryData {
+    /// Move back so the chunk after `chunk_id` is the next one, e.g. to resend what may be lost
+    pub fn rewind(&mut self, chunk_id: u16) {
+        self.current_chunk_id = chunk_id;
+        self.last_bytes_index = chunk_id
+            .saturating_mul(self.chunk_size)
+            .min(self.data.len() as u16);
+    }
+}
+
+/// Firmware binary is only downloaded over http or https from a url with a host
+pub fn validate_url(url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => Ok(()),
+        _ => Err(format!("url {url:?} must be an http or https url")),
+    }
+}
+
 pub fn download_binary(url: &String) -> Result<BinaryData, Box<dyn Error>> {
     debug!("Download binary from {url}");
+    let started = Instant::now();
     let body = reqwest::blocking::get(url)?;
     match body.status() {
         StatusCode::OK => {
             let data = body.bytes()?;
+            metrics()
+                .download_seconds
+                .observe(started.elapsed().as_secs_f64());
+            metrics().download_bytes.observe(data.len() as f64);
             let hash = hash_image(&data);
             Ok(BinaryData {
-                data: Bytes::from(data),
+                data,
                 last_bytes_index: 0,
                 current_chunk_id: 0,
+                chunk_size: settings().chunk_size_per_transmission,
                 hash,
             })
         }
-        s => return Err(Box::new(CustomError::HttpRequest(s.as_u16()))),
+        s => Err(Box::new(CustomError::HttpRequest(s.as_u16()))),
     }
 }
 
@@ -84,6 +111,38 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("http://domain.com:7777/bin/test3.txt").is_ok());
+        assert!(validate_url("https://a/b.bin").is_ok());
+        assert!(validate_url("").is_err());
+        assert!(validate_url("ftp://a/b.bin").is_err());
+        assert!(validate_url("/bin/b.bin").is_err());
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut image = BinaryData {
+            data: Bytes::from("0123456789abcdefghij"),
+            chunk_size: 5,
+            ..Default::default()
+        };
+        let chunk_size = 5;
+        let first = image.next().unwrap();
+        image.next().unwrap();
+        assert_eq!(image.current_chunk_id, 2);
+
+        image.rewind(1);
+        assert_eq!(image.current_chunk_id, 1);
+        assert_eq!(
+            image.next().unwrap(),
+            image.data.slice(chunk_size..2 * chunk_size)
+        );
+
+        image.rewind(0);
+        assert_eq!(image.next(), Some(first));
+    }
+
     #[test]
     fn test_hash_image_failed() {
         let img = Bytes::from("Hello, world?");
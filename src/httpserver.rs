@@ -1,125 +1,1235 @@
+use axum::body::Bytes;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::rejection::{BytesRejection, PathRejection};
+use axum::extract::{DefaultBodyLimit, Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::{middleware, Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::sync::mpsc;
-use std::{
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
-};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
 
-use crate::jobs::NewJob;
-use crate::settings::settings;
+use crate::assignment::{Assignments, FirmwareAssignment};
+use crate::auth::{self, ApiKeys};
+use crate::device_registry::{Device, DeviceRegistry};
+use crate::file_handler;
+use crate::job_board::{DeviceJobs, JobBoard, JobRecord};
+use crate::jobs::{JobId, JobStatus, NewJob};
+use crate::metrics::metrics;
+use crate::settings::{settings, ConflictPolicy};
+use crate::telemetry::Qos;
+use crate::tls::HttpTls;
+use crate::webhook::{self, WebhookDelivery, Webhooks};
 
+/// Error returned by every route, always rendered as `{"error": "..."}`
 #[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    pub(crate) fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.message,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct JobCreated {
+    #[schema(value_type = Uuid)]
+    job_id: JobId,
+}
+
+#[derive(Serialize, ToSchema)]
+struct GroupJobsCreated {
+    #[schema(value_type = Vec<Uuid>)]
+    job_ids: Vec<JobId>,
+    /// Device whose job couldn't be handed over to the scheduler, the job is failed on the board
+    failed_device_ids: Vec<String>,
+    /// Device that already has an unfinished job, skipped with `job_conflict_policy` reject
+    rejected_device_ids: Vec<String>,
+}
+
+/// Same as a job, for every device in the group
+#[derive(Debug, Deserialize, ToSchema)]
+struct GroupJob {
+    /// Where the device image firmware binary is downloaded from
+    url: String,
+    /// Override `mqtt_qos_command` for these jobs, 0, 1 or 2
+    #[serde(default)]
+    #[schema(value_type = Option<u8>, minimum = 0, maximum = 2)]
+    command_qos: Option<Qos>,
+    /// Override `mqtt_qos_data` for these jobs, 0, 1 or 2
+    #[serde(default)]
+    #[schema(value_type = Option<u8>, minimum = 0, maximum = 2)]
+    data_qos: Option<Qos>,
+    /// Hardware model the firmware is built for, checked against the device registry
+    hardware_model: Option<String>,
+    /// Version of the firmware, recorded on the device registry once the job succeeds
+    firmware_version: Option<String>,
+    /// Posted to on every status change of these jobs, host must be in `webhook_callback_hosts`
+    callback_url: Option<String>,
+}
+
+impl GroupJob {
+    fn for_device(&self, device_id: &str) -> NewJob {
+        NewJob {
+            device_id: device_id.to_string(),
+            url: self.url.clone(),
+            command_qos: self.command_qos,
+            data_qos: self.data_qos,
+            hardware_model: self.hardware_model.clone(),
+            firmware_version: self.firmware_version.clone(),
+            callback_url: self.callback_url.clone(),
+            trace_context: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct DeliveryFilter {
+    /// Only deliveries of this job
+    #[param(value_type = Option<Uuid>)]
+    job_id: Option<JobId>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct DeviceFilter {
+    /// Only devices in this group
+    group: Option<String>,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Rocky",
+        description = "Backend service for managing OTA firmware updates via MQTT"
+    ),
+    modifiers(&BearerAuth),
+    security(("bearer" = []))
+)]
+struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    ch_new_job: mpsc::Sender<(JobId, NewJob)>,
+    board: JobBoard,
+    registry: DeviceRegistry,
+    assignments: Assignments,
+    webhooks: Webhooks,
+    keys: ApiKeys,
+    conflict_policy: ConflictPolicy,
+    callback_hosts: Vec<String>,
+}
+
 pub struct HTTPServer {
+    runtime: Runtime,
     listener: TcpListener,
-    ch_new_job: mpsc::Sender<NewJob>,
+    router: Router,
+    tls: Option<(HttpTls, RustlsConfig)>,
 }
 
 impl HTTPServer {
-    pub fn new(tx_new_job: mpsc::Sender<NewJob>) -> Self {
-        let listener =
-            TcpListener::bind(format!("{}:{}", settings().http_host, settings().http_port))
-                .unwrap();
+    pub fn new(
+        tx_new_job: mpsc::Sender<(JobId, NewJob)>,
+        board: JobBoard,
+        registry: DeviceRegistry,
+        assignments: Assignments,
+        webhooks: Webhooks,
+    ) -> Self {
+        // Http server gets its own async runtime, the rest of the service stays on plain threads
+        let runtime = Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(TcpListener::bind(format!(
+                "{}:{}",
+                settings().http_host,
+                settings().http_port
+            )))
+            .unwrap();
         info!(
             "Bind {} on port {}",
             settings().http_host,
             settings().http_port
         );
+
+        let keys = ApiKeys::from_settings(settings())
+            .unwrap_or_else(|err| panic!("Invalid api token configuration ({err})"));
+        if keys.is_disabled() {
+            warn!("http_auth_disabled is set, http api is open to anyone who can reach it");
+        }
+
+        let state = AppState {
+            ch_new_job: tx_new_job,
+            board,
+            registry,
+            assignments,
+            webhooks,
+            keys,
+            conflict_policy: settings().job_conflict_policy,
+            callback_hosts: settings().webhook_callback_hosts.clone(),
+        };
+        let tls = match (&settings().http_tls_cert, &settings().http_tls_key) {
+            (Some(cert), Some(key)) => {
+                let tls = HttpTls {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                    client_ca: settings().http_tls_client_ca.clone(),
+                };
+                let config = tls
+                    .server_config()
+                    .unwrap_or_else(|err| panic!("Invalid http tls configuration ({err})"));
+                Some((tls, RustlsConfig::from_config(config)))
+            }
+            // Setting validation makes sure both are set or none
+            _ => None,
+        };
+
         Self {
+            runtime,
             listener,
-            ch_new_job: tx_new_job,
+            router: router(state, settings().http_max_body_bytes),
+            tls,
         }
     }
 
-    pub fn run(&mut self) {
-        info!("Running http server");
-        for stream in self.listener.incoming() {
-            // TODO: Better error handling
-            let stream = stream.unwrap();
-            self.handle_connection(stream);
+    pub fn run(self) {
+        let Self {
+            runtime,
+            listener,
+            router,
+            tls,
+        } = self;
+        let result = runtime.block_on(async {
+            match tls {
+                Some((tls, config)) => {
+                    info!("Running https server");
+                    tokio::spawn(tls.reload_on_sighup(config.clone()));
+                    let handle = axum_server::Handle::new();
+                    tokio::spawn({
+                        let handle = handle.clone();
+                        async move {
+                            shutdown_signal().await;
+                            handle.graceful_shutdown(Some(Duration::from_secs(10)));
+                        }
+                    });
+                    axum_server::from_tcp_rustls(listener.into_std()?, config)
+                        .handle(handle)
+                        .serve(router.into_make_service())
+                        .await
+                }
+                None => {
+                    info!("Running http server");
+                    axum::serve(listener, router)
+                        .with_graceful_shutdown(shutdown_signal())
+                        .await
+                }
+            }
+        });
+        if let Err(err) = result {
+            error!("Http server stopped ({err})");
         }
     }
+}
 
-    fn handle_connection(&self, mut stream: TcpStream) {
-        let mut buf_reader = BufReader::new(&mut stream);
-        let mut request_line = String::new();
-
-        // Request line
-        buf_reader.read_line(&mut request_line).unwrap();
-        debug!("request_line: {request_line}");
-
-        // println!("{http_request:#?}");
-        if request_line == "POST /job HTTP/1.1\r\n" {
-            let response = if let Ok(_) = self.handle_post_job(buf_reader) {
-                String::from("HTTP/1.1 201 Created\r\n\r\n")
-            } else {
-                String::from("HTTP/1.1 400 Bad Request\r\n\r\n")
-            };
-            stream.write_all(response.as_bytes()).unwrap();
-        } else {
-            // some other request
-            let status_line = "HTTP/1.1 404 NOT FOUND";
-            // let contents = fs::read_to_string("404.html").unwrap();
-            let contents = String::from("404 NOT FOUND");
-            let length = contents.len();
-
-            let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
-
-            stream.write_all(response.as_bytes()).unwrap();
+/// Resolves on SIGINT or SIGTERM, request still in flight is answered before the server stops
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                error!("Listen for SIGTERM failed ({err})");
+                std::future::pending::<()>().await;
+            }
         }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
     }
+    info!("Shutdown signal received, stopping http server");
+}
 
-    fn handle_post_job(
-        &self,
-        mut reader: BufReader<&mut TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // Read header and get the content length
-        let mut list_header: Vec<String> = Vec::new();
-        let mut content_len: u32 = 0;
-        loop {
-            let mut line = String::new();
-            reader.read_line(&mut line).unwrap();
-            if line == "\r\n" {
-                break;
-            }
+/// Every documented route, spec and router are both generated from this so they can't drift apart
+fn api_router() -> OpenApiRouter<AppState> {
+    OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .routes(routes!(post_job))
+        .routes(routes!(get_job))
+        .routes(routes!(post_group_job))
+        .routes(routes!(post_device, list_devices))
+        .routes(routes!(get_device))
+        .routes(routes!(get_device_jobs))
+        .routes(routes!(post_assignment, list_assignments))
+        .routes(routes!(openapi_json))
+        .routes(routes!(get_metrics))
+        .routes(routes!(list_webhook_deliveries))
+}
 
-            if line.len() > 15 {
-                if &line[0..14] == "Content-Length" {
-                    trace!("Content-Length found");
-                    content_len = self.get_content_length(&line[..line.len() - 2]);
-                }
+fn router(state: AppState, max_body_bytes: usize) -> Router {
+    let (router, _) = api_router().split_for_parts();
+    router
+        .fallback(not_found)
+        .method_not_allowed_fallback(method_not_allowed)
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .layer(middleware::from_fn_with_state(
+            state.keys.clone(),
+            auth::authorize,
+        ))
+        .layer(middleware::from_fn(trace_request))
+        .with_state(state)
+}
+
+/// Create new job for a device
+#[utoipa::path(
+    post,
+    path = "/job",
+    request_body = NewJob,
+    responses(
+        (status = 201, description = "Job is queued", body = JobCreated),
+        (status = 400, description = "Invalid request body", body = ErrorBody),
+        (status = 409, description = "Device already has a job, with `job_conflict_policy` reject", body = ErrorBody),
+        (status = 413, description = "Request body too large", body = ErrorBody),
+    )
+)]
+async fn post_job(
+    State(state): State<AppState>,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<(StatusCode, Json<JobCreated>), ApiError> {
+    let job: NewJob = parse_body(body)?;
+    job.validate().map_err(|err| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid request body ({err})"),
+        )
+    })?;
+    validate_callback_url(&state, &job.callback_url)?;
+    if let Some(previous) = rejected_conflict(&state, &job.device_id) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("Device already has job {previous}"),
+        ));
+    }
+    let job_id = queue_job(&state, job)?;
+    tracing::Span::current().record("job_id", tracing::field::display(job_id));
+    Ok((StatusCode::CREATED, Json(JobCreated { job_id })))
+}
+
+/// Create a job for every registered device in a group
+#[utoipa::path(
+    post,
+    path = "/group/{group}/job",
+    params(("group" = String, Path, description = "Device group")),
+    request_body = GroupJob,
+    responses(
+        (status = 201, description = "Jobs are queued, device that already has a job is listed in `rejected_device_ids` with `job_conflict_policy` reject", body = GroupJobsCreated),
+        (status = 400, description = "Invalid request body", body = ErrorBody),
+        (status = 404, description = "No device in the group", body = ErrorBody),
+        (status = 503, description = "No job could be queued, job scheduler is not running", body = ErrorBody),
+    )
+)]
+async fn post_group_job(
+    State(state): State<AppState>,
+    Path(group): Path<String>,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<(StatusCode, Json<GroupJobsCreated>), ApiError> {
+    let job: GroupJob = parse_body(body)?;
+    file_handler::validate_url(&job.url).map_err(|err| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid request body ({err})"),
+        )
+    })?;
+    validate_callback_url(&state, &job.callback_url)?;
+    let devices = state.registry.list(Some(&group));
+    if devices.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "No device in the group",
+        ));
+    }
+
+    // Jobs already queued stay queued when a later one fails, the caller is told about both
+    let mut created = GroupJobsCreated {
+        job_ids: Vec::new(),
+        failed_device_ids: Vec::new(),
+        rejected_device_ids: Vec::new(),
+    };
+    let mut error = None;
+    for device in &devices {
+        if rejected_conflict(&state, &device.device_id).is_some() {
+            created.rejected_device_ids.push(device.device_id.clone());
+            continue;
+        }
+        match queue_job(&state, job.for_device(&device.device_id)) {
+            Ok(job_id) => created.job_ids.push(job_id),
+            Err(err) => {
+                created.failed_device_ids.push(device.device_id.clone());
+                error = Some(err);
             }
+        }
+    }
+    match error {
+        Some(err) if created.job_ids.is_empty() => Err(err),
+        _ => Ok((StatusCode::CREATED, Json(created))),
+    }
+}
+
+/// Register a device or replace its registration
+#[utoipa::path(
+    post,
+    path = "/devices",
+    request_body = Device,
+    responses(
+        (status = 201, description = "Device is registered", body = Device),
+        (status = 200, description = "Device registration is replaced", body = Device),
+        (status = 400, description = "Invalid request body", body = ErrorBody),
+    )
+)]
+async fn post_device(
+    State(state): State<AppState>,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<(StatusCode, Json<Device>), ApiError> {
+    let device: Device = parse_body(body)?;
+    device.validate().map_err(|err| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid request body ({err})"),
+        )
+    })?;
+
+    let status = match state.registry.upsert(device.clone()) {
+        true => StatusCode::CREATED,
+        false => StatusCode::OK,
+    };
+    Ok((status, Json(device)))
+}
+
+/// List registered devices
+#[utoipa::path(
+    get,
+    path = "/devices",
+    params(DeviceFilter),
+    responses(
+        (status = 200, description = "Registered devices sorted by id", body = Vec<Device>),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+    )
+)]
+async fn list_devices(
+    State(state): State<AppState>,
+    filter: Result<Query<DeviceFilter>, QueryRejection>,
+) -> Result<Json<Vec<Device>>, ApiError> {
+    let Query(filter) =
+        filter.map_err(|rejection| ApiError::new(rejection.status(), rejection.body_text()))?;
+    Ok(Json(state.registry.list(filter.group.as_deref())))
+}
+
+/// Get a registered device
+#[utoipa::path(
+    get,
+    path = "/devices/{device_id}",
+    params(("device_id" = String, Path, description = "Device id")),
+    responses(
+        (status = 200, description = "Registered device", body = Device),
+        (status = 404, description = "Device not registered", body = ErrorBody),
+    )
+)]
+async fn get_device(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+) -> Result<Json<Device>, ApiError> {
+    state
+        .registry
+        .get(&device_id)
+        .map(Json)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Device not registered"))
+}
+
+/// Every job of a device with its outcome, oldest first
+#[utoipa::path(
+    get,
+    path = "/devices/{device_id}/jobs",
+    params(("device_id" = String, Path, description = "Device id")),
+    responses((status = 200, description = "Job history of the device", body = DeviceJobs))
+)]
+async fn get_device_jobs(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+) -> Json<DeviceJobs> {
+    Json(state.board.device_jobs(&device_id))
+}
+
+/// Assign firmware to a hardware model, devices asking for update get it
+#[utoipa::path(
+    post,
+    path = "/assignments",
+    request_body = FirmwareAssignment,
+    responses(
+        (status = 201, description = "Firmware is assigned", body = FirmwareAssignment),
+        (status = 200, description = "Assignment of the model and group is replaced", body = FirmwareAssignment),
+        (status = 400, description = "Invalid request body", body = ErrorBody),
+    )
+)]
+async fn post_assignment(
+    State(state): State<AppState>,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<(StatusCode, Json<FirmwareAssignment>), ApiError> {
+    let assignment: FirmwareAssignment = parse_body(body)?;
+    assignment.validate().map_err(|err| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid request body ({err})"),
+        )
+    })?;
+
+    let status = match state.assignments.upsert(assignment.clone()) {
+        true => StatusCode::CREATED,
+        false => StatusCode::OK,
+    };
+    Ok((status, Json(assignment)))
+}
+
+/// List firmware assignments
+#[utoipa::path(
+    get,
+    path = "/assignments",
+    responses((status = 200, description = "Assignments sorted by model and group", body = Vec<FirmwareAssignment>))
+)]
+async fn list_assignments(State(state): State<AppState>) -> Json<Vec<FirmwareAssignment>> {
+    Json(state.assignments.list())
+}
+
+/// Json body with the same error whatever went wrong, axum rejection is plain text otherwise
+fn parse_body<T: DeserializeOwned>(body: Result<Bytes, BytesRejection>) -> Result<T, ApiError> {
+    let body =
+        body.map_err(|rejection| ApiError::new(rejection.status(), rejection.body_text()))?;
+    debug!("content: {}", String::from_utf8_lossy(&body));
+
+    serde_json::from_slice(&body).map_err(|err| {
+        error!("{err}");
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid request body ({err})"),
+        )
+    })
+}
+
+fn validate_callback_url(state: &AppState, url: &Option<String>) -> Result<(), ApiError> {
+    match url {
+        Some(url) => webhook::validate_callback_url(url, &state.callback_hosts)
+            .map_err(|reason| ApiError::new(StatusCode::BAD_REQUEST, reason)),
+        None => Ok(()),
+    }
+}
+
+/// Unfinished job of the device when new job for it has to be refused
+fn rejected_conflict(state: &AppState, device_id: &str) -> Option<JobId> {
+    if state.conflict_policy != ConflictPolicy::Reject {
+        return None;
+    }
+    state.board.unfinished_job(device_id)
+}
+
+/// Span around every request, `job_id` is recorded on it once a job is queued
+async fn trace_request(request: Request, next: Next) -> Response {
+    let span = tracing::info_span!(
+        "http_request",
+        "http.request.method" = %request.method(),
+        "url.path" = request.uri().path(),
+        "http.response.status_code" = tracing::field::Empty,
+        job_id = tracing::field::Empty,
+    );
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    response
+}
+
+/// Register the job on the board and hand it over to the scheduler
+fn queue_job(state: &AppState, mut job: NewJob) -> Result<JobId, ApiError> {
+    job.trace_context = Some(tracing::Span::current().context());
+    let job_id = state.board.register(&job);
+    state.ch_new_job.send((job_id, job)).map_err(|_| {
+        let reason = "job scheduler is not running";
+        state
+            .board
+            .set_status(&job_id, JobStatus::Failed, Some(reason));
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Job scheduler is not running",
+        )
+    })?;
+    Ok(job_id)
+}
+
+/// Get job status
+#[utoipa::path(
+    get,
+    path = "/job/{job_id}",
+    params(("job_id" = Uuid, Path, description = "Job id returned when the job is created")),
+    responses(
+        (status = 200, description = "Job status", body = JobRecord),
+        (status = 400, description = "Invalid job id", body = ErrorBody),
+        (status = 404, description = "Job not found", body = ErrorBody),
+    )
+)]
+async fn get_job(
+    State(state): State<AppState>,
+    job_id: Result<Path<JobId>, PathRejection>,
+) -> Result<Json<JobRecord>, ApiError> {
+    let Path(job_id) =
+        job_id.map_err(|rejection| ApiError::new(rejection.status(), rejection.body_text()))?;
+    state
+        .board
+        .get(&job_id)
+        .map(Json)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Job not found"))
+}
+
+/// Webhook delivery log, most recent 1000 deliveries oldest first
+#[utoipa::path(
+    get,
+    path = "/webhooks/deliveries",
+    params(DeliveryFilter),
+    responses(
+        (status = 200, description = "Webhook deliveries", body = Vec<WebhookDelivery>),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+    )
+)]
+async fn list_webhook_deliveries(
+    State(state): State<AppState>,
+    filter: Result<Query<DeliveryFilter>, QueryRejection>,
+) -> Result<Json<Vec<WebhookDelivery>>, ApiError> {
+    let Query(filter) =
+        filter.map_err(|rejection| ApiError::new(rejection.status(), rejection.body_text()))?;
+    Ok(Json(state.webhooks.deliveries(filter.job_id)))
+}
+
+/// Prometheus metrics of jobs, mqtt publish and firmware download
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((status = 200, description = "Prometheus text exposition format", content_type = "text/plain"))
+)]
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    metrics().set_jobs(state.board.statuses());
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().render(),
+    )
+}
+
+/// OpenAPI document of this api
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((status = 200, description = "OpenAPI 3 document", content_type = "application/json"))
+)]
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(openapi())
+}
+
+pub fn openapi() -> utoipa::openapi::OpenApi {
+    api_router().split_for_parts().1
+}
+
+async fn not_found() -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "Not found")
+}
+
+async fn method_not_allowed() -> ApiError {
+    ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Method, Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    type TestRouter = (
+        Router,
+        mpsc::Receiver<(JobId, NewJob)>,
+        JobBoard,
+        DeviceRegistry,
+    );
+
+    fn test_router(max_body_bytes: usize) -> TestRouter {
+        test_router_with_keys(max_body_bytes, ApiKeys::disabled())
+    }
+
+    fn test_router_with_keys(max_body_bytes: usize, keys: ApiKeys) -> TestRouter {
+        test_router_with(max_body_bytes, keys, ConflictPolicy::Queue)
+    }
+
+    fn test_router_with(
+        max_body_bytes: usize,
+        keys: ApiKeys,
+        conflict_policy: ConflictPolicy,
+    ) -> TestRouter {
+        let (tx, rx) = mpsc::channel();
+        let board = JobBoard::default();
+        let registry = DeviceRegistry::default();
+        let state = AppState {
+            ch_new_job: tx,
+            board: board.clone(),
+            registry: registry.clone(),
+            assignments: Assignments::default(),
+            webhooks: Webhooks::default(),
+            keys,
+            conflict_policy,
+            callback_hosts: vec![String::from("hook")],
+        };
+        (router(state, max_body_bytes), rx, board, registry)
+    }
+
+    fn test_keys() -> ApiKeys {
+        ApiKeys::new(&[
+            auth::ApiToken {
+                token: String::from("reader"),
+                role: auth::Role::ReadOnly,
+            },
+            auth::ApiToken {
+                token: String::from("operator"),
+                role: auth::Role::Operator,
+            },
+        ])
+    }
+
+    fn with_token(mut request: Request<Body>, token: &str) -> Request<Body> {
+        request.headers_mut().insert(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+        request
+    }
+
+    fn post(uri: &str, body: &'static str) -> Request<Body> {
+        Request::post(uri)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn json_body(response: Response) -> serde_json::Value {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_post_job_created() {
+        let (app, rx, board, _) = test_router(1024);
+        let response = app
+            .oneshot(post(
+                "/job",
+                r#"{"device_id":"musang","url":"http://a/b.bin"}"#,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = json_body(response).await;
+        let (job_id, _) = rx.try_recv().unwrap();
+        assert_eq!(body["job_id"], job_id.to_string());
+        assert_eq!(board.get(&job_id).unwrap().device_id, "musang");
+    }
+
+    #[tokio::test]
+    async fn test_post_job_conflict_rejected() {
+        let (app, rx, board, _) =
+            test_router_with(1024, ApiKeys::disabled(), ConflictPolicy::Reject);
+        let previous = board.register(&NewJob {
+            device_id: String::from("musang"),
+            url: String::from("http://a/b.bin"),
+            ..Default::default()
+        });
+        let response = app
+            .oneshot(post(
+                "/job",
+                r#"{"device_id":"musang","url":"http://a/c.bin"}"#,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = json_body(response).await;
+        assert_eq!(body["error"], format!("Device already has job {previous}"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_job_device_id_not_a_topic_level() {
+        let (app, rx, _, _) = test_router(1024);
+        let response = app
+            .oneshot(post(
+                "/job",
+                r#"{"device_id":"musang/#","url":"http://a/b.bin"}"#,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_job_invalid_url() {
+        let (app, rx, _, _) = test_router(1024);
+        let response = app
+            .oneshot(post(
+                "/job",
+                r#"{"device_id":"musang","url":"ftp://a/b.bin"}"#,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_job_invalid_body() {
+        let (app, rx, _, _) = test_router(1024);
+        let response = app
+            .oneshot(post("/job", r#"{"device_id":1}"#))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = json_body(response).await;
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid request body"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_job_callback_url() {
+        let (app, rx, board, _) = test_router(1024);
+        let response = app
+            .clone()
+            .oneshot(post(
+                "/job",
+                r#"{"device_id":"musang","url":"http://a/b.bin","callback_url":"ftp://hook"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(rx.try_recv().is_err());
+
+        // Only hosts of webhook_callback_hosts
+        let response = app
+            .clone()
+            .oneshot(post(
+                "/job",
+                r#"{"device_id":"musang","url":"http://a/b.bin","callback_url":"http://10.0.0.1/job"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(rx.try_recv().is_err());
+
+        let response = app
+            .oneshot(post(
+                "/job",
+                r#"{"device_id":"musang","url":"http://a/b.bin","callback_url":"https://hook/job"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let (job_id, _) = rx.try_recv().unwrap();
+        let record = board.get(&job_id).unwrap();
+        assert_eq!(record.callback_url.as_deref(), Some("https://hook/job"));
+    }
+
+    #[tokio::test]
+    async fn test_post_job_body_too_large() {
+        let (app, _, _, _) = test_router(16);
+        let response = app
+            .oneshot(post(
+                "/job",
+                r#"{"device_id":"musang","url":"http://a/b.bin"}"#,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_get_job() {
+        let (app, _, board, _) = test_router(1024);
+        let job_id = board.register(&NewJob {
+            device_id: String::from("musang"),
+            url: String::from("http://a/b.bin"),
+            ..Default::default()
+        });
+        let request = Request::get(format!("/job/{job_id}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["status"], "on_queue");
+    }
+
+    #[tokio::test]
+    async fn test_register_and_list_devices() {
+        let (app, _, _, registry) = test_router(1024);
+        let device = r#"{"device_id":"musang","hardware_model":"esp32","groups":["lab"]}"#;
+        let response = app.clone().oneshot(post("/devices", device)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = app.clone().oneshot(post("/devices", device)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            registry.get("musang").unwrap().hardware_model.as_deref(),
+            Some("esp32")
+        );
 
-            list_header.push(line[..line.len() - 2].into());
+        for (uri, expected) in [("/devices?group=lab", 1), ("/devices?group=field", 0)] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                json_body(response).await.as_array().unwrap().len(),
+                expected
+            );
         }
 
-        debug!("header: {list_header:#?}");
-        debug!("cl: {content_len}");
+        let request = Request::get("/devices/musang").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(json_body(response).await["groups"][0], "lab");
+    }
 
-        // Read the content
-        let mut buf = vec![0; content_len as usize];
-        reader.read_exact(&mut buf).unwrap();
-        let content = String::from_utf8(buf).expect("Not a valid utf8");
-        debug!("content: {content}");
+    #[tokio::test]
+    async fn test_register_invalid_device() {
+        let (app, _, _, registry) = test_router(1024);
+        let response = app
+            .oneshot(post("/devices", r#"{"device_id":"musang","chunk_size":0}"#))
+            .await
+            .unwrap();
 
-        match self.parse_content(content) {
-            Ok(job) => {
-                self.ch_new_job.send(job).unwrap();
-                Ok(())
-            }
-            Err(err) => {
-                error!("{err}");
-                Err("Invalid request body")?
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(registry.get("musang").is_none());
+
+        let (app, _, _, registry) = test_router(1024);
+        let response = app
+            .oneshot(post("/devices", r#"{"device_id":"lab/+"}"#))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(registry.list(None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_post_group_job() {
+        let (app, rx, _, registry) = test_router(1024);
+        for (device_id, group) in [("a", "lab"), ("b", "lab"), ("c", "field")] {
+            registry.upsert(Device {
+                device_id: String::from(device_id),
+                groups: vec![String::from(group)],
+                ..Default::default()
+            });
+        }
+
+        let body = r#"{"url":"http://a/b.bin","hardware_model":"esp32"}"#;
+        let response = app
+            .clone()
+            .oneshot(post("/group/lab/job", body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            json_body(response).await["job_ids"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        let devices: Vec<_> = rx.try_iter().map(|(_, job)| job.device_id).collect();
+        assert_eq!(devices, ["a", "b"]);
+
+        let response = app
+            .clone()
+            .oneshot(post("/group/none/job", body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Nothing queued at all is an error, every job is failed on the board
+        drop(rx);
+        let response = app.oneshot(post("/group/lab/job", body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_post_group_job_conflict_rejected() {
+        let (app, rx, board, registry) =
+            test_router_with(1024, ApiKeys::disabled(), ConflictPolicy::Reject);
+        for device_id in ["a", "b"] {
+            registry.upsert(Device {
+                device_id: String::from(device_id),
+                groups: vec![String::from("lab")],
+                ..Default::default()
+            });
+        }
+        board.register(&NewJob {
+            device_id: String::from("a"),
+            url: String::from("http://a/b.bin"),
+            ..Default::default()
+        });
+
+        let response = app
+            .oneshot(post("/group/lab/job", r#"{"url":"http://a/c.bin"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = json_body(response).await;
+        assert_eq!(body["job_ids"].as_array().unwrap().len(), 1);
+        assert_eq!(body["rejected_device_ids"], serde_json::json!(["a"]));
+        assert_eq!(body["failed_device_ids"], serde_json::json!([]));
+        let devices: Vec<_> = rx.try_iter().map(|(_, job)| job.device_id).collect();
+        assert_eq!(devices, ["b"]);
+    }
+
+    #[tokio::test]
+    async fn test_assign_firmware() {
+        let (app, _, _, _) = test_router(1024);
+        let body =
+            r#"{"hardware_model":"esp32","firmware_version":"1.0.0","url":"http://a/b.bin"}"#;
+        let response = app
+            .clone()
+            .oneshot(post("/assignments", body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let request = Request::get("/assignments").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(json_body(response).await[0]["firmware_version"], "1.0.0");
+
+        let body = r#"{"hardware_model":"esp32","firmware_version":"","url":"http://a/b.bin"}"#;
+        let response = app
+            .clone()
+            .oneshot(post("/assignments", body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = r#"{"hardware_model":"esp32","firmware_version":"1.0.0","url":""}"#;
+        let response = app.oneshot(post("/assignments", body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_device_jobs() {
+        let (app, _, board, _) = test_router(1024);
+        let job_id = board.register(&NewJob {
+            device_id: String::from("musang"),
+            url: String::from("http://a/b.bin"),
+            ..Default::default()
+        });
+        board.set_status(&job_id, JobStatus::Failed, Some("request denied"));
+
+        let request = Request::get("/devices/musang/jobs")
+            .body(Body::empty())
+            .unwrap();
+        let body = json_body(app.oneshot(request).await.unwrap()).await;
+        assert_eq!(body["jobs"][0]["job_id"], job_id.to_string());
+        assert_eq!(body["jobs"][0]["failure_reason"], "request denied");
+        assert!(body["last_installed_hash"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_route() {
+        let (app, _, _, _) = test_router(1024);
+        let response = app.oneshot(post("/jobs", "{}")).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json_body(response).await["error"], "Not found");
+    }
+
+    #[tokio::test]
+    async fn test_auth_missing_or_unknown_token() {
+        for token in [None, Some("nope")] {
+            let (app, rx, _, _) = test_router_with_keys(1024, test_keys());
+            let mut request = post("/job", r#"{"device_id":"musang","url":"http://a/b.bin"}"#);
+            if let Some(token) = token {
+                request = with_token(request, token);
             }
+            let response = app.oneshot(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert!(rx.try_recv().is_err());
         }
     }
 
-    fn parse_content(&self, content: String) -> Result<NewJob, serde_json::Error> {
-        let new_job: NewJob = serde_json::from_str(&content)?;
-        Ok(new_job)
+    #[tokio::test]
+    async fn test_auth_read_only_cannot_create_job() {
+        let (app, rx, _, _) = test_router_with_keys(1024, test_keys());
+        let request = post("/job", r#"{"device_id":"musang","url":"http://a/b.bin"}"#);
+        let response = app.oneshot(with_token(request, "reader")).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(rx.try_recv().is_err());
     }
 
-    fn get_content_length(&self, str: &str) -> u32 {
-        let splitted: Vec<&str> = str.split(":").collect();
-        let len_str = splitted[1].trim();
-        len_str.parse::<u32>().unwrap()
+    #[tokio::test]
+    async fn test_auth_roles_allowed() {
+        let (app, _rx, _, _) = test_router_with_keys(1024, test_keys());
+        let request = post("/job", r#"{"device_id":"musang","url":"http://a/b.bin"}"#);
+        let response = app
+            .clone()
+            .oneshot(with_token(request, "operator"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let job_id = json_body(response).await["job_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        for token in ["reader", "operator"] {
+            let request = Request::get(format!("/job/{job_id}"))
+                .body(Body::empty())
+                .unwrap();
+            let response = app
+                .clone()
+                .oneshot(with_token(request, token))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_served() {
+        let (app, _, board, _) = test_router(1024);
+        for device_id in ["musang", "luwak"] {
+            board.register(&NewJob {
+                device_id: String::from(device_id),
+                url: String::from("http://a/b.bin"),
+                ..Default::default()
+            });
+        }
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; version=0.0.4"
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("rocky_jobs{status=\"on_queue\"} 2"));
+        assert!(text.contains("rocky_jobs{status=\"success\"} 0"));
+        assert!(text.contains("rocky_mqtt_disconnects_total"));
+    }
+
+    #[tokio::test]
+    async fn test_openapi_served() {
+        let (app, _, _, _) = test_router(1024);
+        let request = Request::get("/openapi.json").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            json_body(response).await,
+            serde_json::to_value(openapi()).unwrap()
+        );
+    }
+
+    /// Every operation in the spec must reach a real handler, not the router fallbacks,
+    /// and every other method on a spec path must reach the method not allowed fallback
+    #[tokio::test]
+    async fn test_openapi_matches_routes() {
+        let spec = openapi();
+        assert!(!spec.paths.paths.is_empty());
+
+        for (path, item) in spec.paths.paths.iter() {
+            let uri = path
+                .replace("{job_id}", &uuid::Uuid::new_v4().to_string())
+                .replace("{device_id}", "musang")
+                .replace("{group}", "lab");
+            let operations = [
+                (Method::GET, &item.get),
+                (Method::POST, &item.post),
+                (Method::PUT, &item.put),
+                (Method::DELETE, &item.delete),
+                (Method::PATCH, &item.patch),
+            ];
+            for (method, operation) in operations {
+                let (app, _, _, _) = test_router(1024);
+                let request = Request::builder()
+                    .method(method.clone())
+                    .uri(&uri)
+                    .body(Body::from("{}"))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                let fallback = serde_json::from_slice::<serde_json::Value>(&body)
+                    .map(|value| {
+                        value["error"] == "Not found" || value["error"] == "Method not allowed"
+                    })
+                    .unwrap_or(false);
+                if operation.is_some() {
+                    assert!(
+                        !fallback,
+                        "{method} {path} is in the spec but not routed ({status})"
+                    );
+                } else {
+                    assert!(
+                        fallback,
+                        "{method} {path} is routed but not in the spec ({status})"
+                    );
+                }
+            }
+        }
     }
 }
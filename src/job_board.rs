@@ -0,0 +1,299 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::jobs::{JobId, JobStatus, NewJob};
+use crate::settings::settings;
+use crate::webhook::Webhooks;
+
+/// Job as it is exposed through the http api
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct JobRecord {
+    #[schema(value_type = Uuid)]
+    pub job_id: JobId,
+    pub device_id: String,
+    pub url: String,
+    pub status: JobStatus,
+    /// Only set when the job is failed
+    pub failure_reason: Option<String>,
+    /// Sha256 of the firmware image as hex, set once it is downloaded
+    pub image_hash: Option<String>,
+    /// Unix timestamp in milliseconds
+    pub created_at: u64,
+    /// Unix timestamp in milliseconds, when `FOTA_REQUEST` is sent
+    pub started_at: Option<u64>,
+    /// Unix timestamp in milliseconds, when the job is success or failed
+    pub finished_at: Option<u64>,
+    /// From start (or creation when it never started) to finish
+    pub duration_ms: Option<u64>,
+    /// Notified on every status change, on top of `webhook_urls`
+    pub callback_url: Option<String>,
+    /// Registration order, timestamps of jobs created in the same millisecond tie
+    #[serde(skip)]
+    sequence: u64,
+}
+
+/// Every job of a device, oldest first
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DeviceJobs {
+    pub device_id: String,
+    /// Image hash of the last successful job, kept after the job itself left the board
+    pub last_installed_hash: Option<String>,
+    pub jobs: Vec<JobRecord>,
+}
+
+/// Status board shared between http server and jobs thread.
+/// Http server registers new job here, jobs thread keeps the status up to date.
+#[derive(Debug, Clone, Default)]
+pub struct JobBoard {
+    records: Arc<RwLock<HashMap<JobId, JobRecord>>>,
+    sequence: Arc<AtomicU64>,
+    /// Image hash of the last successful job by device id
+    installed_hashes: Arc<RwLock<HashMap<String, String>>>,
+    webhooks: Webhooks,
+}
+
+impl JobBoard {
+    /// Board notifying status change through the webhooks
+    pub fn new(webhooks: Webhooks) -> Self {
+        Self {
+            webhooks,
+            ..Default::default()
+        }
+    }
+
+    /// Register new job as on queue and return its id, id is guaranteed unique within the board
+    pub fn register(&self, new_job: &NewJob) -> JobId {
+        let mut records = self.records.write().unwrap();
+        // v4 uuid is random enough to be unguessable, still make sure it never overwrites a job
+        let job_id = loop {
+            let job_id = Uuid::new_v4();
+            if !records.contains_key(&job_id) {
+                break job_id;
+            }
+        };
+
+        records.insert(
+            job_id,
+            JobRecord {
+                job_id,
+                device_id: new_job.device_id.clone(),
+                url: new_job.url.clone(),
+                status: JobStatus::OnQueue,
+                failure_reason: None,
+                image_hash: None,
+                created_at: now_ms(),
+                started_at: None,
+                finished_at: None,
+                duration_ms: None,
+                callback_url: new_job.callback_url.clone(),
+                sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            },
+        );
+        job_id
+    }
+
+    pub fn get(&self, job_id: &JobId) -> Option<JobRecord> {
+        self.records.read().unwrap().get(job_id).cloned()
+    }
+
+    pub fn set_status(&self, job_id: &JobId, status: JobStatus, reason: Option<&str>) {
+        let mut records = self.records.write().unwrap();
+        let Some(record) = records.get_mut(job_id) else {
+            warn!("Job {job_id} is not on the board");
+            return;
+        };
+        record.status = status;
+        record.failure_reason = reason.map(String::from);
+        match status {
+            JobStatus::Starting => record.started_at = Some(now_ms()),
+            JobStatus::Success | JobStatus::Failed => {
+                let finished_at = now_ms();
+                let since = record.started_at.unwrap_or(record.created_at);
+                record.finished_at = Some(finished_at);
+                record.duration_ms = Some(finished_at.saturating_sub(since));
+            }
+            _ => {}
+        }
+        let record = record.clone();
+        if let (JobStatus::Success, Some(hash)) = (status, &record.image_hash) {
+            self.installed_hashes
+                .write()
+                .unwrap()
+                .insert(record.device_id.clone(), hash.clone());
+        }
+        if matches!(status, JobStatus::Success | JobStatus::Failed) {
+            Self::evict_finished(&mut records, settings().job_history_max);
+        }
+        drop(records);
+        self.webhooks.notify(&record);
+    }
+
+    /// Drop the oldest finished jobs above `max`, job still going on is always kept
+    fn evict_finished(records: &mut HashMap<JobId, JobRecord>, max: usize) {
+        let mut finished: Vec<(u64, JobId)> = records
+            .values()
+            .filter(|record| matches!(record.status, JobStatus::Success | JobStatus::Failed))
+            .map(|record| (record.sequence, record.job_id))
+            .collect();
+        if finished.len() <= max {
+            return;
+        }
+        finished.sort_unstable();
+        for (_, job_id) in &finished[..finished.len() - max] {
+            records.remove(job_id);
+        }
+    }
+
+    /// Status of every job on the board
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        let records = self.records.read().unwrap();
+        records.values().map(|record| record.status).collect()
+    }
+
+    /// Job of the device that is neither success nor failed, if any
+    pub fn unfinished_job(&self, device_id: &str) -> Option<JobId> {
+        let records = self.records.read().unwrap();
+        records
+            .values()
+            .filter(|record| record.device_id == device_id)
+            .find(|record| !matches!(record.status, JobStatus::Success | JobStatus::Failed))
+            .map(|record| record.job_id)
+    }
+
+    pub fn set_image_hash(&self, job_id: &JobId, hash: &[u8]) {
+        if let Some(record) = self.records.write().unwrap().get_mut(job_id) {
+            record.image_hash = Some(hash.iter().map(|byte| format!("{byte:02x}")).collect());
+        }
+    }
+
+    pub fn device_jobs(&self, device_id: &str) -> DeviceJobs {
+        let records = self.records.read().unwrap();
+        let mut jobs: Vec<JobRecord> = records
+            .values()
+            .filter(|record| record.device_id == device_id)
+            .cloned()
+            .collect();
+        jobs.sort_by_key(|record| record.sequence);
+
+        let last_installed_hash = self
+            .installed_hashes
+            .read()
+            .unwrap()
+            .get(device_id)
+            .cloned();
+        DeviceJobs {
+            device_id: device_id.to_string(),
+            last_installed_hash,
+            jobs,
+        }
+    }
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_job(device_id: &str) -> NewJob {
+        NewJob {
+            device_id: String::from(device_id),
+            url: String::from("http://a/b.bin"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_device_jobs() {
+        let board = JobBoard::default();
+        let installed = board.register(&new_job("musang"));
+        let failed = board.register(&new_job("musang"));
+        board.register(&new_job("other"));
+
+        board.set_image_hash(&installed, &[0xab, 0x01]);
+        board.set_status(&installed, JobStatus::Starting, None);
+        board.set_status(&installed, JobStatus::Success, None);
+        board.set_image_hash(&failed, &[0xcd, 0x02]);
+        board.set_status(&failed, JobStatus::Failed, Some("request denied"));
+
+        let history = board.device_jobs("musang");
+        assert_eq!(history.jobs.len(), 2);
+        assert_eq!(history.last_installed_hash.as_deref(), Some("ab01"));
+        let record = board.get(&failed).unwrap();
+        assert_eq!(record.failure_reason.as_deref(), Some("request denied"));
+        assert!(record.started_at.is_none());
+        assert!(record.duration_ms.is_some());
+
+        assert!(board.device_jobs("nobody").jobs.is_empty());
+    }
+
+    #[test]
+    fn test_installed_hash_outlives_history() {
+        let board = JobBoard::default();
+        let installed = board.register(&new_job("musang"));
+        board.set_image_hash(&installed, &[0xab, 0x01]);
+        board.set_status(&installed, JobStatus::Success, None);
+        for _ in 0..3 {
+            let job_id = board.register(&new_job("musang"));
+            board.set_status(&job_id, JobStatus::Failed, Some("request denied"));
+        }
+
+        JobBoard::evict_finished(&mut board.records.write().unwrap(), 2);
+        let history = board.device_jobs("musang");
+        assert!(history.jobs.iter().all(|record| record.job_id != installed));
+        assert_eq!(history.last_installed_hash.as_deref(), Some("ab01"));
+    }
+
+    #[test]
+    fn test_device_jobs_in_registration_order() {
+        let board = JobBoard::default();
+        let job_ids: Vec<JobId> = (0..20)
+            .map(|_| board.register(&new_job("musang")))
+            .collect();
+
+        let history: Vec<JobId> = board
+            .device_jobs("musang")
+            .jobs
+            .iter()
+            .map(|record| record.job_id)
+            .collect();
+        assert_eq!(history, job_ids);
+    }
+
+    #[test]
+    fn test_evict_oldest_finished() {
+        let board = JobBoard::default();
+        let job_ids: Vec<JobId> = (0..4).map(|_| board.register(&new_job("musang"))).collect();
+        for job_id in &job_ids[1..] {
+            board.set_status(job_id, JobStatus::Success, None);
+        }
+
+        let mut records = board.records.write().unwrap();
+        JobBoard::evict_finished(&mut records, 2);
+        // Unfinished job stays whatever its age
+        assert!(records.contains_key(&job_ids[0]));
+        assert!(!records.contains_key(&job_ids[1]));
+        assert!(records.contains_key(&job_ids[2]));
+        assert!(records.contains_key(&job_ids[3]));
+    }
+
+    #[test]
+    fn test_unfinished_job() {
+        let board = JobBoard::default();
+        let job_id = board.register(&new_job("musang"));
+        assert_eq!(board.unfinished_job("musang"), Some(job_id));
+
+        board.set_status(&job_id, JobStatus::Success, None);
+        assert_eq!(board.unfinished_job("musang"), None);
+    }
+}
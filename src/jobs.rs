@@ -1,20 +1,30 @@
-use crate::custom_error::CustomError;
-use crate::file_handler::{download_binary, BinaryData};
-use crate::messenger::Messenger;
-use crate::settings::settings;
-use crate::telemetry::{self, CommandType, Telemetry};
+use crate::assignment::Assignments;
+use crate::custom_error::{CustomError, TelemetryError};
+use crate::device_registry::{Device, DeviceRegistry};
+use crate::file_handler::{self, download_binary, BinaryData};
+use crate::job_board::JobBoard;
+use crate::messenger::{ConnectionState, DeliveryId, Messenger};
+use crate::metrics::metrics;
+use crate::settings::{settings, ConflictPolicy};
+use crate::telemetry::{self, CommandType, Presence, Qos, Telemetry, UpdateCheck};
+use crate::topic::{self, topics};
 use core::time;
 use rand::Rng;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
 use std::sync::mpsc;
 use std::time::{Duration, Instant};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
     thread,
 };
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use utoipa::ToSchema;
+use uuid::Uuid;
 
-#[derive(Debug, PartialEq, Eq)]
-enum JobStatus {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
     Success,
     Failed,
     Finishing,
@@ -23,51 +33,155 @@ enum JobStatus {
     OnQueue,
 }
 
-pub type JobId = u16;
+impl JobStatus {
+    pub const ALL: [JobStatus; 6] = [
+        JobStatus::Success,
+        JobStatus::Failed,
+        JobStatus::Finishing,
+        JobStatus::InProgress,
+        JobStatus::Starting,
+        JobStatus::OnQueue,
+    ];
+
+    /// Same name as the serialized one
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Success => "success",
+            JobStatus::Failed => "failed",
+            JobStatus::Finishing => "finishing",
+            JobStatus::InProgress => "in_progress",
+            JobStatus::Starting => "starting",
+            JobStatus::OnQueue => "on_queue",
+        }
+    }
+}
+
+pub type JobId = Uuid;
+/// Compact identifier of a running transfer, this is what goes on the wire and the device echoes back
+pub type TransferToken = u32;
 
 #[derive(Debug)]
 pub struct Job {
     job_id: JobId,
+    token: Option<TransferToken>,
     device_id: String,
     status: JobStatus,
     url: String,
     image: BinaryData,
+    command_qos: Qos,
+    data_qos: Qos,
+    hardware_model: Option<String>,
+    /// Model the device reported itself in an update check, used when the registry has none
+    reported_hardware_model: Option<String>,
+    firmware_version: Option<String>,
+    /// Last chunk acknowledged by the broker along with every chunk before it,
+    /// resumed from after a disconnect
+    confirmed_chunk_id: u16,
+    /// Chunk published but not acknowledged yet, with the time it was published
+    unacked: BTreeMap<u16, Instant>,
+    /// Consecutive publish failure, reset on every fota command sent and every chunk acknowledged
+    send_failures: u32,
     last_time_processed: Instant,
+    /// Open until the job is done, work done for the job is nested in it
+    span: tracing::Span,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, ToSchema)]
 pub struct NewJob {
-    device_id: String,
-    url: String,
+    /// Target device for this job
+    pub device_id: String,
+    /// Where the device image firmware binary is downloaded from
+    pub url: String,
+    /// Override `mqtt_qos_command` for this job, 0, 1 or 2
+    #[serde(default)]
+    #[schema(value_type = Option<u8>, minimum = 0, maximum = 2)]
+    pub command_qos: Option<Qos>,
+    /// Override `mqtt_qos_data` for this job, e.g. 0 for device on limited bandwidth
+    #[serde(default)]
+    #[schema(value_type = Option<u8>, minimum = 0, maximum = 2)]
+    pub data_qos: Option<Qos>,
+    /// Hardware model the firmware is built for, checked against the device registry
+    pub hardware_model: Option<String>,
+    /// Version of the firmware, recorded on the device registry once the job succeeds
+    pub firmware_version: Option<String>,
+    /// Posted to on every status change, on top of `webhook_urls`,
+    /// host must be in `webhook_callback_hosts`
+    pub callback_url: Option<String>,
+    /// Trace of whatever created the job, the job span is parented to it
+    #[serde(skip)]
+    pub trace_context: Option<opentelemetry::Context>,
+}
+
+impl NewJob {
+    pub fn validate(&self) -> Result<(), String> {
+        topic::validate_level(&self.device_id).map_err(|reason| format!("device_id {reason}"))?;
+        file_handler::validate_url(&self.url)
+    }
 }
 
 pub struct JobScheduler {
     jobs: HashMap<JobId, Job>,
+    tokens: HashMap<TransferToken, JobId>,
     on_queue: VecDeque<JobId>,
     running: Vec<JobId>,
     starting_job: Option<JobId>,
     finishing_job: Option<JobId>,
     last_running_job_index: u8, // TODO: Change this type
+    rejected_notifications: u64,
+    /// Job waits for its device to be online, set when `mqtt_topic_presence` is
+    track_presence: bool,
+    /// Last known presence of each device, only kept when presence is tracked
+    presence: HashMap<String, bool>,
+    /// Chunk publish waiting for broker acknowledgement
+    deliveries: HashMap<DeliveryId, (JobId, u16)>,
+    /// Chunk is sent again when the broker doesn't acknowledge it within this
+    ack_timeout: Duration,
+    /// What a new job does to the unfinished jobs its device already has
+    conflict_policy: ConflictPolicy,
     messenger: Messenger,
+    connection: ConnectionState,
+    was_connected: bool,
+    board: JobBoard,
+    registry: DeviceRegistry,
+    assignments: Assignments,
     ch_notification: mpsc::Receiver<Telemetry>, // TODO: Change name to ch_notification
-    ch_new_job: mpsc::Receiver<NewJob>,
+    ch_delivery: mpsc::Receiver<DeliveryId>,
+    ch_new_job: mpsc::Receiver<(JobId, NewJob)>,
 }
 
 impl JobScheduler {
     pub fn new(
         messenger: Messenger,
         rx_notification: mpsc::Receiver<Telemetry>,
-        rx_new_job: mpsc::Receiver<NewJob>,
+        rx_delivery: mpsc::Receiver<DeliveryId>,
+        rx_new_job: mpsc::Receiver<(JobId, NewJob)>,
+        board: JobBoard,
+        registry: DeviceRegistry,
+        assignments: Assignments,
     ) -> Self {
+        let connection = messenger.connection_state();
         Self {
             jobs: HashMap::new(),
+            tokens: HashMap::new(),
             on_queue: VecDeque::new(),
             running: Vec::new(),
             starting_job: None,
             finishing_job: None,
             last_running_job_index: 0,
+            rejected_notifications: 0,
+            track_presence: topics().presence.is_some(),
+            presence: HashMap::new(),
+            deliveries: HashMap::new(),
+            ack_timeout: Duration::from_millis(settings().job_ack_timeout_ms),
+            conflict_policy: settings().job_conflict_policy,
             messenger,
+            connection,
+            was_connected: false,
+            board,
+            registry,
+            assignments,
             ch_notification: rx_notification,
+            ch_delivery: rx_delivery,
             ch_new_job: rx_new_job,
         }
     }
@@ -80,18 +194,31 @@ impl JobScheduler {
     fn _run(mut self) {
         let max_running_job: usize = settings().job_max_running.into();
         loop {
-            if let Ok(new_job) = self.ch_new_job.recv_timeout(Duration::from_millis(10)) {
-                info!("Receive new job: {new_job:?}");
-                self.add_job(new_job);
+            if let Ok((job_id, new_job)) = self.ch_new_job.recv_timeout(Duration::from_millis(10)) {
+                info!("Receive new job {job_id}: {new_job:?}");
+                self.add_job(job_id, new_job);
             }
 
             if let Ok(notif) = self.ch_notification.recv_timeout(Duration::from_millis(10)) {
                 self.handle_notification(notif);
             }
 
+            while let Ok(delivery_id) = self.ch_delivery.try_recv() {
+                self.handle_delivery(delivery_id);
+            }
+
+            metrics().jobs_queued.set(self.on_queue.len() as i64);
+            metrics().jobs_running.set(self.running.len() as i64);
+
+            // Nothing is published while the broker is down, running jobs just wait for it
+            if !self.check_connection() {
+                thread::sleep(Duration::from_millis(settings().job_processed_interval_ms));
+                continue;
+            }
+
             // Start job from on_queue job list if running list not in max number
             if self.running.len() < max_running_job {
-                if let Err(msg) = self.start_job_onqueue() {
+                if let Err(msg) = self.start_next_job() {
                     error!("Starting job err ({msg})");
                     // TODO: do somekind of interval for checking this if statement
                 }
@@ -105,39 +232,132 @@ impl JobScheduler {
             };
 
             debug!("Next Job: {next_job_id}");
-            self.process_job(next_job_id);
+            let span = self.job_span(next_job_id);
+            span.in_scope(|| self.process_job(next_job_id));
 
             thread::sleep(Duration::from_millis(10)); // To give breath to the cpu
         }
     }
 
-    fn add_job(&mut self, new_job: NewJob) {
-        // Add new job to the on_queue list
-        let job_id = Self::generate_job_id();
+    fn add_job(&mut self, job_id: JobId, new_job: NewJob) {
+        // Not nested in whatever span is current, only in the trace the job came from
+        let span =
+            tracing::info_span!(parent: None, "job", %job_id, device_id = %new_job.device_id);
+        if let Some(trace_context) = new_job.trace_context {
+            span.set_parent(trace_context);
+        }
+
+        // Add new job to the on_queue list, id is already registered on the board
         self.jobs.insert(
             job_id,
             Job {
                 job_id,
+                token: None,
                 device_id: new_job.device_id,
                 status: JobStatus::OnQueue,
                 url: new_job.url,
                 image: BinaryData::default(),
+                command_qos: new_job.command_qos.unwrap_or(settings().mqtt_qos_command),
+                data_qos: new_job.data_qos.unwrap_or(settings().mqtt_qos_data),
+                hardware_model: new_job.hardware_model,
+                reported_hardware_model: None,
+                firmware_version: new_job.firmware_version,
+                confirmed_chunk_id: 0,
+                unacked: BTreeMap::new(),
+                send_failures: 0,
                 last_time_processed: Instant::now(),
+                span,
             },
         );
 
         self.on_queue.push_back(job_id);
         trace!("New job added {:#?}", self.jobs.get(&job_id));
+        self.resolve_conflict(job_id);
     }
 
-    fn start_job_onqueue(&mut self) -> Result<(), CustomError> {
-        // Get job that still on queue.
+    /// Only one job per device may be active, apply the conflict policy to the jobs it already has
+    fn resolve_conflict(&mut self, job_id: JobId) {
+        let device_id = &self.jobs[&job_id].device_id;
+        let started = self
+            .jobs
+            .values()
+            .find(|job| {
+                job.device_id == *device_id
+                    && !matches!(
+                        job.status,
+                        JobStatus::OnQueue | JobStatus::Success | JobStatus::Failed
+                    )
+            })
+            .map(|job| job.job_id);
+        // In queue order, so the outcome doesn't depend on the map iteration
+        let queued: Vec<JobId> = self
+            .on_queue
+            .iter()
+            .copied()
+            .filter(|queued| {
+                *queued != job_id
+                    && self.jobs.get(queued).is_some_and(|job| {
+                        job.device_id == *device_id && job.status == JobStatus::OnQueue
+                    })
+            })
+            .collect();
+        let Some(previous) = started.or(queued.first().copied()) else {
+            return;
+        };
+
+        match self.conflict_policy {
+            ConflictPolicy::Reject => {
+                self.failed_job(job_id, &format!("device already has job {previous}"));
+            }
+            ConflictPolicy::Replace => {
+                for queued in queued {
+                    self.failed_job(queued, &format!("replaced by job {job_id}"));
+                }
+                // Started job can't be replaced, the new one waits like any other queued job
+                if let Some(started) = started {
+                    info!("Job {job_id} waits for job {started} of the same device");
+                }
+            }
+            ConflictPolicy::Queue => {
+                info!("Job {job_id} waits for job {previous} of the same device");
+            }
+        }
+    }
+
+    /// Device has a job being sent, another one for it must wait
+    fn has_active_job(&self, device_id: &str) -> bool {
+        self.jobs.values().any(|job| {
+            job.device_id == device_id
+                && matches!(
+                    job.status,
+                    JobStatus::Starting | JobStatus::InProgress | JobStatus::Finishing
+                )
+        })
+    }
+
+    /// Start the first job on queue whose device is online and idle
+    fn start_next_job(&mut self) -> Result<(), CustomError> {
+        // Get job that still on queue, job of device that isn't online or already busy stays there.
         // Only get reference since, needs to process it first before removing it from the queue
-        let Some(job_id) = self.on_queue.pop_front() else {
-            trace!("No job in the queue");
+        let Some(position) = self.on_queue.iter().position(|job_id| {
+            self.jobs.get(job_id).is_none_or(|job| {
+                self.is_online(&job.device_id) && !self.has_active_job(&job.device_id)
+            })
+        }) else {
+            trace!("No job in the queue for online and idle device");
             return Ok(());
         };
+        let job_id = self.on_queue.remove(position).unwrap_or_default();
+        // Traced only once a job is picked, the queue is checked on every loop
+        let span = self.job_span(job_id);
+        span.in_scope(|| self.start_job_onqueue(job_id))
+    }
 
+    #[tracing::instrument(
+        skip(self),
+        fields(device_id = self.jobs.get(&job_id).map(|job| job.device_id.as_str()))
+    )]
+    fn start_job_onqueue(&mut self, job_id: JobId) -> Result<(), CustomError> {
         // Try to get job data (as mutable reference) to be modified later
         let Some(job) = self.jobs.get_mut(&job_id) else {
             return Err(CustomError::StartJob(format!(
@@ -146,27 +366,64 @@ impl JobScheduler {
             )));
         };
 
+        // Firmware built for another hardware must never reach the device
+        let device = self.registry.get(&job.device_id);
+        if let Some(reason) = Self::hardware_mismatch(job, device.as_ref()) {
+            self.failed_job(job_id, &reason);
+            return Err(CustomError::StartJob(reason));
+        }
+
         // Attempt to download the binary from url provided
         debug!("Attempt download binary image of job {job_id}");
-        match download_binary(&job.url) {
+        let download = tracing::info_span!(
+            "download_binary",
+            %job_id,
+            device_id = %job.device_id,
+            url = %job.url
+        );
+        match download.in_scope(|| download_binary(&job.url)) {
             Ok(data) => {
                 // Now the binary already on the heap (BinaryData) and ready to chunked
                 job.image = data;
+                self.board.set_image_hash(&job_id, &job.image.hash);
+                if let Some(chunk_size) = device.and_then(|device| device.chunk_size) {
+                    job.image.chunk_size = chunk_size;
+                }
+                // Assign transfer token that the device will echo back for the rest of the job
+                let token = Self::generate_token(&self.tokens);
+                self.tokens.insert(token, job_id);
+                job.token = Some(token);
                 // Send fota request command to target device
-                // TODO: Send hash too
-                let tosend = telemetry::build_command(
-                    job_id,
+                let tosend = match telemetry::build_command(
+                    token,
                     &job.device_id,
                     CommandType::OtaRequest,
                     &job.image.hash,
-                )
-                .unwrap(); // TODO: handle error
-                let _ = self.messenger.send(tosend); // TODO: Handle error
+                    job.command_qos,
+                ) {
+                    Ok(tosend) => tosend,
+                    Err(err) => {
+                        let reason = format!("build fota request failed ({err})");
+                        self.failed_job(job_id, &reason);
+                        return Err(CustomError::StartJob(reason));
+                    }
+                };
+                if let Err(err) = self.messenger.send(tosend) {
+                    // Put it back to the front of the queue with a new token for the next attempt
+                    job.token = None;
+                    self.tokens.remove(&token);
+                    self.on_queue.push_front(job_id);
+                    self.publish_failed(job_id, "fota request", err);
+                    return Ok(());
+                }
+                job.send_failures = 0;
+                // Handshake latency is measured from here until the device accepts
+                job.last_time_processed = Instant::now();
                 debug!("fota request is sent to {}", job.device_id);
 
                 // Set the job as starting, also change the status on the real data
                 self.starting_job = Some(job_id);
-                job.status = JobStatus::Starting;
+                self.set_status(job_id, JobStatus::Starting, None);
                 info!("Job {job_id} is starting");
 
                 Ok(())
@@ -185,18 +442,24 @@ impl JobScheduler {
             return; // TODO: Better error
         };
 
+        // Still holds the time fota request is sent
+        metrics()
+            .handshake_seconds
+            .observe(job.last_time_processed.elapsed().as_secs_f64());
+        // Set the last time job is processed
+        job.last_time_processed = Instant::now();
+
         // Add the job to running index list
-        self.running.push(job_id.clone());
+        self.running.push(job_id);
         // Reset starting job to empty
         self.starting_job = None;
         // Change the actual job data status to in progres
-        job.status = JobStatus::InProgress;
+        self.set_status(job_id, JobStatus::InProgress, None);
         info!("Job {job_id} now in progress");
-
-        // Set the last time job is processed
-        job.last_time_processed = Instant::now();
     }
 
+    /// Runs every `job_processed_interval_ms` per job, not traced on its own so a transfer
+    /// doesn't export a span per chunk, logs inside it still carry the job span
     fn process_job(&mut self, job_id: JobId) {
         let Some(job) = self.jobs.get_mut(&job_id) else {
             // TODO: Return custom error
@@ -207,6 +470,21 @@ impl JobScheduler {
             return;
         };
 
+        // Chunks are published in order, so the first one is the oldest
+        if job
+            .unacked
+            .first_key_value()
+            .is_some_and(|(_, published)| published.elapsed() >= self.ack_timeout)
+        {
+            debug!("Job {job_id} resume from chunk {}", job.confirmed_chunk_id);
+            job.image.rewind(job.confirmed_chunk_id);
+            job.unacked.clear();
+            self.deliveries
+                .retain(|_, (delivery_job, _)| *delivery_job != job_id);
+            self.publish_failed(job_id, "chunk", "broker ack timed out".into());
+            return;
+        }
+
         // Delay before process the next job when interval is not met yet
         thread::sleep(JobScheduler::get_job_interval_delay(
             job_id,
@@ -214,31 +492,77 @@ impl JobScheduler {
         ));
         job.last_time_processed = Instant::now(); // Set the new clock
 
+        let previous_chunk_id = job.image.current_chunk_id;
         match job.image.next() {
             Some(chunk) => {
                 // Send fota request command to target device
                 debug!("data of {} => {:?}", job_id, job.image.last_bytes_index);
-                let tosend =
-                    telemetry::build_packet(&job.device_id, job.image.current_chunk_id, chunk);
-                let _ = self.messenger.send(tosend); // TODO: Handle error
+                let tosend = telemetry::build_packet(
+                    &job.device_id,
+                    job.image.current_chunk_id,
+                    chunk,
+                    job.data_qos,
+                );
+                let chunk_len = tosend.payload.len();
+                match self.messenger.send(tosend) {
+                    Ok(delivery_id) => {
+                        metrics().chunks_published.inc();
+                        metrics().bytes_published.inc_by(chunk_len as u64);
+                        job.unacked
+                            .insert(job.image.current_chunk_id, Instant::now());
+                        self.deliveries
+                            .insert(delivery_id, (job_id, job.image.current_chunk_id));
+                    }
+                    Err(err) => {
+                        // Chunk is sent again on the next round
+                        job.image.rewind(previous_chunk_id);
+                        self.publish_failed(job_id, "chunk", err);
+                    }
+                }
             }
             None => {
-                if let Some(_) = self.finishing_job {
+                // A chunk only counts as sent once the broker acknowledged it
+                if job.confirmed_chunk_id < job.image.current_chunk_id {
+                    debug!(
+                        "Job {job_id} wait broker ack from chunk {} to {}",
+                        job.confirmed_chunk_id + 1,
+                        job.image.current_chunk_id
+                    );
+                    return;
+                }
+                if self.finishing_job.is_some() {
                     info!("Currently there's still job in finishing status {job_id}");
                     return;
                 };
 
+                // Token 0 is never assigned, a job without one can't be finished on the wire
+                let Some(token) = job.token else {
+                    self.failed_job(job_id, "transfer token is missing");
+                    return;
+                };
+
                 // Finishing the job
                 info!("Finishing job {job_id}");
-                let tosend = telemetry::build_command(
-                    job_id,
+                let tosend = match telemetry::build_command(
+                    token,
                     &job.device_id,
                     CommandType::OtaDone,
                     &Vec::new(), // Just send empty vector, done command don't need image hash in the payload
-                );
-                let _ = self.messenger.send(tosend.unwrap());
+                    job.command_qos,
+                ) {
+                    Ok(tosend) => tosend,
+                    Err(err) => {
+                        self.failed_job(job_id, &format!("build fota done failed ({err})"));
+                        return;
+                    }
+                };
+                if let Err(err) = self.messenger.send(tosend) {
+                    self.publish_failed(job_id, "fota done", err);
+                    return;
+                }
+                job.send_failures = 0;
                 // remove job from running list and change job status on hashmap
-                job.status = JobStatus::Finishing;
+                self.set_status(job_id, JobStatus::Finishing, None);
                 self.running.remove(self.last_running_job_index.into());
                 // Add the job to finishing job candidate
                 self.finishing_job = Some(job_id);
@@ -246,9 +570,72 @@ impl JobScheduler {
         }
     }
 
+    /// Follow broker connection, on disconnect every running job goes back to its last
+    /// confirmed chunk since whatever was sent right before may never reach the device
+    fn check_connection(&mut self) -> bool {
+        let connected = self.connection.is_connected();
+        if connected == self.was_connected {
+            return connected;
+        }
+        self.was_connected = connected;
+
+        if connected {
+            info!(
+                "Broker connected, resume {} running job",
+                self.running.len()
+            );
+            return true;
+        }
+        warn!(
+            "Broker disconnected, pause {} running job",
+            self.running.len()
+        );
+        for job_id in &self.running {
+            if let Some(job) = self.jobs.get_mut(job_id) {
+                debug!("Job {job_id} resume from chunk {}", job.confirmed_chunk_id);
+                job.image.rewind(job.confirmed_chunk_id);
+                job.unacked.clear();
+            }
+        }
+        // Rewound chunks are published again with new delivery id
+        self.deliveries.clear();
+        false
+    }
+
+    /// Broker acknowledged a publish, confirmed chunk moves up to the first one still unacknowledged
+    fn handle_delivery(&mut self, delivery_id: DeliveryId) {
+        let Some((job_id, chunk_id)) = self.deliveries.remove(&delivery_id) else {
+            return; // Command or chunk from before a disconnect, nothing to track
+        };
+        if let Some(job) = self.jobs.get_mut(&job_id) {
+            trace!("Job {job_id} chunk {chunk_id} acknowledged by broker");
+            job.unacked.remove(&chunk_id);
+            job.send_failures = 0;
+            job.confirmed_chunk_id = match job.unacked.first_key_value() {
+                Some((first, _)) => first - 1,
+                None => job.image.current_chunk_id,
+            };
+        }
+    }
+
+    /// Count consecutive publish failure, the job fails once it reach `job_max_send_failures`
+    fn publish_failed(&mut self, job_id: JobId, what: &str, err: Box<dyn Error>) {
+        let Some(job) = self.jobs.get_mut(&job_id) else {
+            return;
+        };
+        job.send_failures += 1;
+        warn!(
+            "Publish {what} of job {job_id} failed {} time ({err})",
+            job.send_failures
+        );
+        if job.send_failures >= settings().job_max_send_failures {
+            self.failed_job(job_id, &format!("publish {what} failed repeatedly"));
+        }
+    }
+
     fn get_next_job(&mut self) -> Option<JobId> {
         // Return directly when running job list is empty
-        if self.running.len() == 0 {
+        if self.running.is_empty() {
             return None;
         }
 
@@ -258,37 +645,308 @@ impl JobScheduler {
         let Some(value) = self.running.get(idx as usize) else {
             // println!("Already on last index");
             self.last_running_job_index = 0;
-            return self.running.get(0).copied();
+            return self.running.first().copied();
         };
 
         // Use the next index, keep the index to last variable
         self.last_running_job_index = idx;
-        Some(value.clone())
+        Some(*value)
     }
 
     fn failed_job(&mut self, job_id: JobId, reason: &str) {
-        let Some(job) = self.jobs.get_mut(&job_id) else {
+        let Some(job) = self.jobs.get(&job_id) else {
             return; // TODO: better error
         };
-        job.status = JobStatus::Failed;
-        self.starting_job = None;
         warn!(
             "Job {} for device_id {} failed ({})",
-            job_id, job.device_id, reason
+            job.job_id, job.device_id, reason
+        );
+        self.set_status(job_id, JobStatus::Failed, Some(reason));
+    }
+
+    /// Change job status and mirror it to the board.
+    /// Job reaching final status is only kept on the board, the scheduler forgets it with its
+    /// image and transfer token.
+    fn set_status(&mut self, job_id: JobId, status: JobStatus, reason: Option<&str>) {
+        let Some(job) = self.jobs.get_mut(&job_id) else {
+            return;
+        };
+        let from = std::mem::replace(&mut job.status, status);
+        self.board.set_status(&job_id, status, reason);
+
+        let duration_ms = self
+            .board
+            .get(&job_id)
+            .and_then(|record| record.duration_ms);
+        tracing::info!(
+            %job_id,
+            device_id = %job.device_id,
+            from = from.as_str(),
+            to = status.as_str(),
+            reason,
+            duration_ms,
+            "Job status changed"
         );
+        if matches!(status, JobStatus::Success | JobStatus::Failed) {
+            self.forget_job(job_id);
+        }
+    }
+
+    /// Drop every trace of a finished job, its span ends once the work still inside it is done
+    fn forget_job(&mut self, job_id: JobId) {
+        let Some(job) = self.jobs.remove(&job_id) else {
+            return;
+        };
+        if let Some(token) = job.token {
+            self.tokens.remove(&token);
+        }
+        if self.starting_job == Some(job_id) {
+            self.starting_job = None;
+        }
+        if self.finishing_job == Some(job_id) {
+            self.finishing_job = None;
+        }
+        self.running.retain(|running| *running != job_id);
+        self.on_queue.retain(|queued| *queued != job_id);
+        self.deliveries
+            .retain(|_, (delivery_job, _)| *delivery_job != job_id);
+    }
+
+    /// Span carrying the job and device id, every log inside it is tagged with them
+    fn job_span(&self, job_id: JobId) -> tracing::Span {
+        match self.jobs.get(&job_id) {
+            Some(job) => job.span.clone(),
+            None => tracing::Span::none(),
+        }
+    }
+
+    /// Reason to refuse the job when its firmware is built for another hardware model than the
+    /// device, or when the device model isn't known so it can't be checked
+    fn hardware_mismatch(job: &Job, device: Option<&Device>) -> Option<String> {
+        let expected = job.hardware_model.as_deref()?;
+        let Some(actual) = device
+            .and_then(|device| device.hardware_model.as_deref())
+            .or(job.reported_hardware_model.as_deref())
+        else {
+            return Some(format!(
+                "firmware is for hardware model {expected}, device {} has no registered model",
+                job.device_id
+            ));
+        };
+        (expected != actual)
+            .then(|| format!("firmware is for hardware model {expected}, device is {actual}"))
+    }
+
+    /// Device is known online, always true when presence isn't tracked
+    fn is_online(&self, device_id: &str) -> bool {
+        !self.track_presence || self.presence.get(device_id).copied().unwrap_or(false)
+    }
+
+    fn handle_presence(&mut self, presence: Presence) {
+        let was_online = self
+            .presence
+            .insert(presence.device_id.clone(), presence.online);
+        if was_online == Some(presence.online) {
+            return;
+        }
+        let queued = self
+            .on_queue
+            .iter()
+            .filter_map(|job_id| self.jobs.get(job_id))
+            .filter(|job| job.device_id == presence.device_id)
+            .count();
+        if presence.online {
+            info!(
+                "Device {} is online, {queued} job on queue",
+                presence.device_id
+            );
+        } else {
+            info!(
+                "Device {} is offline, {queued} job held on queue",
+                presence.device_id
+            );
+        }
+    }
+
+    /// Device woke up and asks for update, its pending job goes first, otherwise a job is
+    /// created when its model is assigned another firmware. Device is told when it is up to date.
+    fn handle_update_check(&mut self, check: UpdateCheck) {
+        info!(
+            "Device {} with {} running {} asks for update",
+            check.device_id, check.hardware_model, check.firmware_version
+        );
+        // Device that asks is obviously online
+        if self.track_presence {
+            self.presence.insert(check.device_id.clone(), true);
+        }
+        let device = self.registry.get(&check.device_id);
+        if device.is_some() {
+            self.registry
+                .set_firmware_version(&check.device_id, &check.firmware_version);
+        }
+
+        let mut device_jobs = self
+            .jobs
+            .values()
+            .filter(|job| job.device_id == check.device_id);
+        if device_jobs.any(|job| {
+            matches!(
+                job.status,
+                JobStatus::Starting | JobStatus::InProgress | JobStatus::Finishing
+            )
+        }) {
+            debug!("Device {} is already being updated", check.device_id);
+            return;
+        }
+        let pending = self.on_queue.iter().copied().find(|job_id| {
+            self.jobs
+                .get(job_id)
+                .is_some_and(|job| job.device_id == check.device_id)
+        });
+        if let Some(job_id) = pending {
+            info!("Job {job_id} of device {} goes first", check.device_id);
+            self.prioritize(job_id);
+            return;
+        }
+
+        let groups = device.map(|device| device.groups).unwrap_or_default();
+        match self.assignments.find(&check.hardware_model, &groups) {
+            Some(assignment) if assignment.firmware_version != check.firmware_version => {
+                let new_job = NewJob {
+                    device_id: check.device_id,
+                    url: assignment.url,
+                    hardware_model: Some(assignment.hardware_model),
+                    firmware_version: Some(assignment.firmware_version),
+                    trace_context: Some(tracing::Span::current().context()),
+                    ..Default::default()
+                };
+                let job_id = self.board.register(&new_job);
+                info!("Assigned firmware job {job_id}: {new_job:?}");
+                self.add_job(job_id, new_job);
+                if let Some(job) = self.jobs.get_mut(&job_id) {
+                    job.reported_hardware_model = Some(check.hardware_model);
+                }
+                self.prioritize(job_id);
+            }
+            _ => {
+                debug!("Device {} is up to date", check.device_id);
+                let tosend = telemetry::build_command(
+                    0, // No transfer, nothing to echo back
+                    &check.device_id,
+                    CommandType::OtaUpToDate,
+                    &Vec::new(),
+                    settings().mqtt_qos_command,
+                );
+                if let Err(err) = tosend.and_then(|tosend| self.messenger.send(tosend)) {
+                    warn!("Send up to date to {} failed ({err})", check.device_id);
+                }
+            }
+        }
+    }
+
+    /// Move a queued job to the front of the queue
+    fn prioritize(&mut self, job_id: JobId) {
+        if !self.on_queue.contains(&job_id) {
+            return;
+        }
+        self.on_queue.retain(|queued| *queued != job_id);
+        self.on_queue.push_front(job_id);
     }
 
+    #[tracing::instrument(
+        skip_all,
+        fields(topic = %notif.topic, device_id = tracing::field::Empty, job_id = tracing::field::Empty)
+    )]
     fn handle_notification(&mut self, notif: Telemetry) {
-        let parsed = telemetry::parse(notif).unwrap(); // TODO: handle error
+        let span = tracing::Span::current();
+        match telemetry::parse_update_check(&notif) {
+            Ok(check) => {
+                span.record("device_id", check.device_id.as_str());
+                return self.handle_update_check(check);
+            }
+            Err(TelemetryError::UnexpectedTopic(_)) => {}
+            Err(err) => {
+                self.rejected_notifications += 1;
+                warn!(
+                    "Rejected update check #{} on topic {} ({err})",
+                    self.rejected_notifications, notif.topic
+                );
+                return;
+            }
+        }
+
+        // Presence goes to the presence table, anything else must be a command response
+        match telemetry::parse_presence(&notif) {
+            Ok(presence) => {
+                span.record("device_id", presence.device_id.as_str());
+                return self.handle_presence(presence);
+            }
+            Err(TelemetryError::UnexpectedTopic(_)) => {}
+            Err(err) => {
+                self.rejected_notifications += 1;
+                warn!(
+                    "Rejected presence #{} on topic {} ({err})",
+                    self.rejected_notifications, notif.topic
+                );
+                return;
+            }
+        }
+
+        let response = match telemetry::parse(&notif) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                // Device input must never take the scheduler down, just count it and move on
+                self.rejected_notifications += 1;
+                warn!(
+                    "Rejected notification #{} on topic {} from device {} ({err})",
+                    self.rejected_notifications,
+                    notif.topic,
+                    notif.device_id()
+                );
+                return;
+            }
+        };
+
+        span.record("device_id", response.device_id.as_str());
+
+        // Resolve the transfer token back to the job it was assigned to
+        let Some(&job_id) = self.tokens.get(&response.token) else {
+            self.rejected_notifications += 1;
+            warn!(
+                "Rejected notification #{} on topic {} from device {} (no active transfer with token {})",
+                self.rejected_notifications, notif.topic, response.device_id, response.token
+            );
+            return;
+        };
+        span.record("job_id", tracing::field::display(job_id));
+
+        // Only the device that owns the job is allowed to drive it
+        if let Some(job) = self.jobs.get(&job_id) {
+            if job.device_id != response.device_id {
+                self.rejected_notifications += 1;
+                warn!(
+                    target: "security",
+                    "Device {} sent {:?} for job {} owned by device {}, rejected (topic {})",
+                    response.device_id,
+                    response.command,
+                    job_id,
+                    job.device_id,
+                    notif.topic
+                );
+                return;
+            }
+        }
+
+        let _span = self.job_span(job_id).entered();
 
         // Check if notification for job that currently starting
         if let Some(starting_job) = self.starting_job {
-            if parsed.0 == starting_job {
+            if job_id == starting_job {
                 debug!("Notification for currently starting job");
-                match parsed.1 {
+                match response.command {
                     CommandType::OtaRequestAck => self.start_job(starting_job),
                     CommandType::OtaRequestNack => self.failed_job(starting_job, "request denied"),
-                    _ => return, // What happen here?,
+                    other => warn!("Unexpected {other:?} for starting job {starting_job}"),
                 }
                 return;
             }
@@ -296,22 +954,30 @@ impl JobScheduler {
 
         // Check if notification for job that currently finishing
         if let Some(finishing_job) = self.finishing_job {
-            if parsed.0 == finishing_job {
+            if job_id == finishing_job {
                 debug!("Notification for currently finishing job");
-                let Some(job) = self.jobs.get_mut(&finishing_job) else {
-                    return; // TODO: Better error
-                };
-
-                match parsed.1 {
+                match response.command {
                     CommandType::OtaDoneSuccess => {
-                        job.status = JobStatus::Success;
-                        info!("Job {} is SUCCESS", job.job_id);
+                        if let Some(job) = self.jobs.get(&finishing_job) {
+                            if let Some(version) = &job.firmware_version {
+                                self.registry.set_firmware_version(&job.device_id, version);
+                            }
+                        }
+                        self.set_status(finishing_job, JobStatus::Success, None);
+                        info!("Job {finishing_job} is SUCCESS");
                     }
                     CommandType::OtaDoneFailed => {
-                        job.status = JobStatus::Failed;
-                        warn!("Job {} is FAILED", job.job_id);
+                        self.set_status(
+                            finishing_job,
+                            JobStatus::Failed,
+                            Some("device reported failure"),
+                        );
+                        warn!("Job {finishing_job} is FAILED");
+                    }
+                    other => {
+                        warn!("Unexpected {other:?} for finishing job {finishing_job}");
+                        return;
                     }
-                    _ => return, // TODO: What happen here,
                 }
 
                 // Whatever the result, consider it finish
@@ -329,6 +995,9 @@ impl JobScheduler {
 
         // Check if interval already passed
         if interval <= elapsed {
+            metrics()
+                .interval_missed_seconds
+                .observe((elapsed - interval).as_secs_f64());
             warn!(
                 "Job id {} interval missed by {:?}",
                 job_id,
@@ -343,8 +1012,512 @@ impl JobScheduler {
         dur
     }
 
-    fn generate_job_id() -> JobId {
+    fn generate_token(active: &HashMap<TransferToken, JobId>) -> TransferToken {
         let mut rng = rand::thread_rng();
-        rng.gen_range(1000..=9999)
+        loop {
+            let token = rng.gen_range(1..=TransferToken::MAX);
+            if !active.contains_key(&token) {
+                return token;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assignment::FirmwareAssignment;
+    use bytes::Bytes;
+    use rumqttc::{Connection, Publish, Request};
+    use std::sync::{Arc, Mutex};
+
+    fn scheduler() -> (JobScheduler, Connection) {
+        let (tx_notification, rx_notification) = mpsc::channel();
+        let (tx_delivery, rx_delivery) = mpsc::channel();
+        let (_, rx_new_job) = mpsc::channel();
+        let (messenger, connection) = Messenger::unpolled(tx_notification, tx_delivery);
+        let mut scheduler = JobScheduler::new(
+            messenger,
+            rx_notification,
+            rx_delivery,
+            rx_new_job,
+            JobBoard::default(),
+            DeviceRegistry::default(),
+            Assignments::default(),
+        );
+        scheduler.was_connected = true;
+        (scheduler, connection)
+    }
+
+    /// Everything published so far, in publish order
+    fn published(connection: &mut Connection) -> Vec<Publish> {
+        connection.eventloop.clean();
+        connection
+            .eventloop
+            .pending
+            .drain(..)
+            .filter_map(|request| match request {
+                Request::Publish(publish) => Some(publish),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn new_job(device_id: &str) -> NewJob {
+        NewJob {
+            device_id: device_id.to_string(),
+            // Refused right away, job that gets started fails its download
+            url: String::from("http://127.0.0.1:1/b.bin"),
+            ..Default::default()
+        }
+    }
+
+    fn queued_job(scheduler: &mut JobScheduler, device_id: &str) -> JobId {
+        let new_job = new_job(device_id);
+        let job_id = scheduler.board.register(&new_job);
+        scheduler.add_job(job_id, new_job);
+        job_id
+    }
+
+    /// Job past the handshake with a 20 bytes image, 4 chunks of 5 bytes
+    fn running_job(scheduler: &mut JobScheduler) -> JobId {
+        let new_job = new_job("musang");
+        let job_id = scheduler.board.register(&new_job);
+        scheduler.add_job(job_id, new_job);
+        scheduler.on_queue.retain(|queued| *queued != job_id);
+        let job = scheduler.jobs.get_mut(&job_id).unwrap();
+        job.image = BinaryData {
+            data: Bytes::from_static(b"0123456789abcdefghij"),
+            chunk_size: 5,
+            ..Default::default()
+        };
+        job.token = Some(7);
+        scheduler.tokens.insert(7, job_id);
+        scheduler.running.push(job_id);
+        scheduler.set_status(job_id, JobStatus::InProgress, None);
+        job_id
+    }
+
+    /// Process the job right away, without waiting `job_processed_interval_ms`
+    fn tick(scheduler: &mut JobScheduler, job_id: JobId) {
+        let job = scheduler.jobs.get_mut(&job_id).unwrap();
+        job.last_time_processed = Instant::now() - Duration::from_secs(1);
+        scheduler.process_job(job_id);
+    }
+
+    /// Status on the board, finished job is no longer in the scheduler
+    fn status(scheduler: &JobScheduler, job_id: JobId) -> JobStatus {
+        scheduler.board.get(&job_id).unwrap().status
+    }
+
+    fn ack(scheduler: &mut JobScheduler, chunk_id: u16) {
+        let delivery_id = scheduler
+            .deliveries
+            .iter()
+            .find(|(_, (_, chunk))| *chunk == chunk_id)
+            .map(|(delivery_id, _)| *delivery_id)
+            .unwrap();
+        scheduler.handle_delivery(delivery_id);
+    }
+
+    #[test]
+    fn test_chunk_confirmed_only_with_every_chunk_before() {
+        let (mut scheduler, mut connection) = scheduler();
+        let job_id = running_job(&mut scheduler);
+        for _ in 0..3 {
+            tick(&mut scheduler, job_id);
+        }
+        let topics: Vec<_> = published(&mut connection)
+            .into_iter()
+            .map(|publish| publish.topic)
+            .collect();
+        assert_eq!(
+            topics,
+            [
+                "/fota/data/musang/1",
+                "/fota/data/musang/2",
+                "/fota/data/musang/3"
+            ]
+        );
+
+        ack(&mut scheduler, 3);
+        ack(&mut scheduler, 2);
+        assert_eq!(scheduler.jobs[&job_id].confirmed_chunk_id, 0);
+        ack(&mut scheduler, 1);
+        assert_eq!(scheduler.jobs[&job_id].confirmed_chunk_id, 3);
+    }
+
+    #[test]
+    fn test_done_waits_for_every_ack() {
+        let (mut scheduler, mut connection) = scheduler();
+        let job_id = running_job(&mut scheduler);
+        for _ in 0..5 {
+            tick(&mut scheduler, job_id);
+        }
+        assert_eq!(published(&mut connection).len(), 4);
+
+        for chunk_id in [4, 3, 2] {
+            ack(&mut scheduler, chunk_id);
+        }
+        tick(&mut scheduler, job_id);
+        assert!(published(&mut connection).is_empty());
+
+        ack(&mut scheduler, 1);
+        tick(&mut scheduler, job_id);
+        assert_eq!(status(&scheduler, job_id), JobStatus::Finishing);
+        assert_eq!(published(&mut connection)[0].topic, "/fota/cmd/musang");
+    }
+
+    #[test]
+    fn test_ack_timeout_resends_from_confirmed_chunk() {
+        let (mut scheduler, mut connection) = scheduler();
+        scheduler.ack_timeout = Duration::from_millis(50);
+        let job_id = running_job(&mut scheduler);
+        tick(&mut scheduler, job_id);
+        tick(&mut scheduler, job_id);
+        ack(&mut scheduler, 1);
+        published(&mut connection);
+
+        thread::sleep(Duration::from_millis(60));
+        tick(&mut scheduler, job_id);
+        let job = &scheduler.jobs[&job_id];
+        assert_eq!(job.image.current_chunk_id, 1);
+        assert_eq!(job.send_failures, 1);
+        assert!(job.unacked.is_empty());
+        assert!(scheduler.deliveries.is_empty());
+
+        tick(&mut scheduler, job_id);
+        assert_eq!(published(&mut connection)[0].topic, "/fota/data/musang/2");
+    }
+
+    #[test]
+    fn test_ack_timeout_fails_job_repeatedly() {
+        let (mut scheduler, _connection) = scheduler();
+        scheduler.ack_timeout = Duration::ZERO;
+        let job_id = running_job(&mut scheduler);
+        for _ in 0..settings().job_max_send_failures {
+            tick(&mut scheduler, job_id);
+            tick(&mut scheduler, job_id);
+        }
+
+        assert_eq!(status(&scheduler, job_id), JobStatus::Failed);
+        assert!(scheduler.running.is_empty());
+    }
+
+    #[test]
+    fn test_job_held_until_device_online() {
+        let (mut scheduler, _connection) = scheduler();
+        scheduler.track_presence = true;
+        let held = queued_job(&mut scheduler, "musang");
+        let other = queued_job(&mut scheduler, "kancil");
+        scheduler.handle_presence(Presence {
+            device_id: String::from("kancil"),
+            online: true,
+        });
+
+        // Job of the online device goes first, download fails so it is done with
+        assert!(scheduler.start_next_job().is_err());
+        assert_eq!(status(&scheduler, other), JobStatus::Failed);
+        assert!(scheduler.start_next_job().is_ok());
+        assert_eq!(status(&scheduler, held), JobStatus::OnQueue);
+        assert_eq!(scheduler.on_queue, [held]);
+
+        scheduler.handle_presence(Presence {
+            device_id: String::from("musang"),
+            online: true,
+        });
+        assert!(scheduler.start_next_job().is_err());
+        assert_eq!(status(&scheduler, held), JobStatus::Failed);
+        assert!(scheduler.on_queue.is_empty());
+    }
+
+    #[test]
+    fn test_hardware_mismatch() {
+        let (mut scheduler, _connection) = scheduler();
+        let job_id = queued_job(&mut scheduler, "musang");
+        let job = scheduler.jobs.get_mut(&job_id).unwrap();
+        let device = |model: Option<&str>| Device {
+            device_id: String::from("musang"),
+            hardware_model: model.map(String::from),
+            ..Default::default()
+        };
+
+        // Nothing to check without an expected model
+        assert!(JobScheduler::hardware_mismatch(job, None).is_none());
+
+        job.hardware_model = Some(String::from("esp32"));
+        assert!(JobScheduler::hardware_mismatch(job, Some(&device(Some("esp32")))).is_none());
+        assert!(JobScheduler::hardware_mismatch(job, Some(&device(Some("nrf52")))).is_some());
+        assert!(JobScheduler::hardware_mismatch(job, Some(&device(None))).is_some());
+        assert!(JobScheduler::hardware_mismatch(job, None).is_some());
+
+        job.reported_hardware_model = Some(String::from("esp32"));
+        assert!(JobScheduler::hardware_mismatch(job, None).is_none());
+    }
+
+    fn update_check(firmware_version: &str) -> UpdateCheck {
+        UpdateCheck {
+            device_id: String::from("musang"),
+            hardware_model: String::from("esp32"),
+            firmware_version: firmware_version.to_string(),
+        }
+    }
+
+    fn assign(scheduler: &JobScheduler, firmware_version: &str) {
+        scheduler.assignments.upsert(FirmwareAssignment {
+            hardware_model: String::from("esp32"),
+            firmware_version: firmware_version.to_string(),
+            url: String::from("http://a/esp32.bin"),
+            group: None,
+        });
+    }
+
+    #[test]
+    fn test_update_check_prioritizes_queued_job() {
+        let (mut scheduler, mut connection) = scheduler();
+        assign(&scheduler, "1.1.0");
+        let other = queued_job(&mut scheduler, "kancil");
+        let pending = queued_job(&mut scheduler, "musang");
+
+        scheduler.handle_update_check(update_check("1.0.0"));
+        assert_eq!(scheduler.on_queue, [pending, other]);
+        assert_eq!(scheduler.jobs.len(), 2);
+        assert!(published(&mut connection).is_empty());
+    }
+
+    #[test]
+    fn test_update_check_creates_assigned_job() {
+        let (mut scheduler, mut connection) = scheduler();
+        assign(&scheduler, "1.1.0");
+        let other = queued_job(&mut scheduler, "kancil");
+
+        scheduler.handle_update_check(update_check("1.0.0"));
+        let job_id = scheduler.on_queue[0];
+        assert_eq!(scheduler.on_queue, [job_id, other]);
+        let job = &scheduler.jobs[&job_id];
+        assert_eq!(job.device_id, "musang");
+        assert_eq!(job.url, "http://a/esp32.bin");
+        assert_eq!(job.firmware_version.as_deref(), Some("1.1.0"));
+        assert_eq!(job.reported_hardware_model.as_deref(), Some("esp32"));
+        assert!(scheduler.board.get(&job_id).is_some());
+        assert!(published(&mut connection).is_empty());
+    }
+
+    #[test]
+    fn test_update_check_answers_up_to_date() {
+        let (mut scheduler, mut connection) = scheduler();
+        assign(&scheduler, "1.1.0");
+
+        scheduler.handle_update_check(update_check("1.1.0"));
+        assert!(scheduler.on_queue.is_empty());
+        let expected = telemetry::build_command(
+            0,
+            "musang",
+            CommandType::OtaUpToDate,
+            &[],
+            settings().mqtt_qos_command,
+        )
+        .unwrap();
+        let sent = published(&mut connection);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].topic, expected.topic);
+        assert_eq!(sent[0].payload, expected.payload);
+    }
+
+    #[test]
+    fn test_update_check_ignored_while_updating() {
+        let (mut scheduler, mut connection) = scheduler();
+        assign(&scheduler, "1.1.0");
+        running_job(&mut scheduler);
+
+        scheduler.handle_update_check(update_check("1.0.0"));
+        assert!(scheduler.on_queue.is_empty());
+        assert_eq!(scheduler.jobs.len(), 1);
+        assert!(published(&mut connection).is_empty());
+    }
+
+    #[test]
+    fn test_disconnect_rewinds_to_confirmed_chunk() {
+        let (mut scheduler, _connection) = scheduler();
+        let job_id = running_job(&mut scheduler);
+        for _ in 0..3 {
+            tick(&mut scheduler, job_id);
+        }
+        ack(&mut scheduler, 1);
+        ack(&mut scheduler, 3);
+
+        scheduler.connection.set_connected(false);
+        assert!(!scheduler.check_connection());
+        let job = &scheduler.jobs[&job_id];
+        assert_eq!(job.image.current_chunk_id, 1);
+        assert!(job.unacked.is_empty());
+        assert!(scheduler.deliveries.is_empty());
+    }
+
+    #[test]
+    fn test_conflict_reject() {
+        let (mut scheduler, _connection) = scheduler();
+        scheduler.conflict_policy = ConflictPolicy::Reject;
+        let first = queued_job(&mut scheduler, "musang");
+        let second = queued_job(&mut scheduler, "musang");
+
+        assert_eq!(status(&scheduler, first), JobStatus::OnQueue);
+        assert_eq!(status(&scheduler, second), JobStatus::Failed);
+        assert_eq!(scheduler.on_queue, [first]);
+    }
+
+    #[test]
+    fn test_conflict_replace_fails_every_queued_job() {
+        let (mut scheduler, _connection) = scheduler();
+        scheduler.conflict_policy = ConflictPolicy::Queue;
+        let first = queued_job(&mut scheduler, "musang");
+        let second = queued_job(&mut scheduler, "musang");
+        let other = queued_job(&mut scheduler, "luwak");
+        scheduler.conflict_policy = ConflictPolicy::Replace;
+        let newest = queued_job(&mut scheduler, "musang");
+
+        assert_eq!(status(&scheduler, first), JobStatus::Failed);
+        assert_eq!(status(&scheduler, second), JobStatus::Failed);
+        assert_eq!(status(&scheduler, newest), JobStatus::OnQueue);
+        assert_eq!(scheduler.on_queue, [other, newest]);
+    }
+
+    #[test]
+    fn test_conflict_replace_waits_for_started_job() {
+        let (mut scheduler, _connection) = scheduler();
+        scheduler.conflict_policy = ConflictPolicy::Replace;
+        let running = running_job(&mut scheduler);
+        let newest = queued_job(&mut scheduler, "musang");
+
+        assert_eq!(status(&scheduler, running), JobStatus::InProgress);
+        assert_eq!(status(&scheduler, newest), JobStatus::OnQueue);
+        assert_eq!(scheduler.on_queue, [newest]);
+    }
+
+    #[test]
+    fn test_conflict_queue() {
+        let (mut scheduler, _connection) = scheduler();
+        scheduler.conflict_policy = ConflictPolicy::Queue;
+        let first = queued_job(&mut scheduler, "musang");
+        let second = queued_job(&mut scheduler, "musang");
+
+        assert_eq!(status(&scheduler, first), JobStatus::OnQueue);
+        assert_eq!(status(&scheduler, second), JobStatus::OnQueue);
+        assert_eq!(scheduler.on_queue, [first, second]);
+    }
+
+    #[test]
+    fn test_job_span_in_creator_trace() {
+        use opentelemetry::trace::{TraceContextExt, TracerProvider};
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let (mut scheduler, _connection) = scheduler();
+            let request = tracing::info_span!("http_request");
+            let mut new_job = new_job("musang");
+            new_job.trace_context = Some(request.context());
+            let job_id = scheduler.board.register(&new_job);
+            scheduler.add_job(job_id, new_job);
+            let untraced = queued_job(&mut scheduler, "luwak");
+
+            let trace_id = |span: tracing::Span| span.context().span().span_context().trace_id();
+            assert_eq!(trace_id(scheduler.job_span(job_id)), trace_id(request));
+            assert_ne!(
+                trace_id(scheduler.job_span(untraced)),
+                trace_id(scheduler.job_span(job_id))
+            );
+        });
+    }
+
+    #[test]
+    fn test_finished_job_is_forgotten() {
+        let (mut scheduler, _connection) = scheduler();
+        let job_id = running_job(&mut scheduler);
+        tick(&mut scheduler, job_id);
+        assert_eq!(scheduler.deliveries.len(), 1);
+
+        scheduler.failed_job(job_id, "request denied");
+        assert_eq!(status(&scheduler, job_id), JobStatus::Failed);
+        assert!(scheduler.jobs.is_empty());
+        assert!(scheduler.tokens.is_empty());
+        assert!(scheduler.running.is_empty());
+        assert!(scheduler.deliveries.is_empty());
+    }
+
+    /// Io writer of the log capture
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Lines logged while `f` runs, formatted as with `log_format = "json"`
+    fn json_logs(f: impl FnOnce()) -> Vec<serde_json::Value> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::logging::json_layer(move || Captured(writer.clone())));
+        tracing::subscriber::with_default(subscriber, f);
+
+        let buffer = buffer.lock().unwrap();
+        String::from_utf8_lossy(&buffer)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    /// Span of the status change log line to `to`
+    fn status_span(lines: &[serde_json::Value], to: &str) -> serde_json::Value {
+        lines
+            .iter()
+            .find(|line| line["message"] == "Job status changed" && line["to"] == to)
+            .map(|line| line["span"].clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_json_logs_carry_job() {
+        let (mut scheduler, _connection) = scheduler();
+        let mut job_ids = Vec::new();
+        let lines = json_logs(|| {
+            // Logged inside process_job, like the run loop does it
+            let job_id = running_job(&mut scheduler);
+            for chunk_id in 1..=5 {
+                let span = scheduler.job_span(job_id);
+                span.in_scope(|| tick(&mut scheduler, job_id));
+                if chunk_id <= 4 {
+                    ack(&mut scheduler, chunk_id);
+                }
+            }
+            job_ids.push(job_id);
+
+            // Logged inside start_job_onqueue, model can't be checked so the job fails
+            let mut new_job = new_job("luwak");
+            new_job.hardware_model = Some(String::from("esp32"));
+            let job_id = scheduler.board.register(&new_job);
+            scheduler.add_job(job_id, new_job);
+            assert!(scheduler.start_next_job().is_err());
+            job_ids.push(job_id);
+        });
+
+        let finishing = status_span(&lines, "finishing");
+        assert_eq!(finishing["job_id"], job_ids[0].to_string());
+        assert_eq!(finishing["device_id"], "musang");
+        let failed = status_span(&lines, "failed");
+        assert_eq!(failed["name"], "start_job_onqueue");
+        assert_eq!(failed["job_id"], job_ids[1].to_string());
+        assert_eq!(failed["device_id"], "luwak");
     }
 }
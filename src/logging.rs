@@ -0,0 +1,62 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::Subscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::otel;
+use crate::settings::LogFormat;
+
+/// Install the global logger, level is taken from `RUST_LOG` in both formats.
+/// Spans are exported to `otel_endpoint` when it is set, whatever the log level is,
+/// the returned provider has to be shut down before exit.
+pub fn init(format: LogFormat, otel_endpoint: Option<&str>) -> Option<SdkTracerProvider> {
+    if format == LogFormat::Text && otel_endpoint.is_none() {
+        pretty_env_logger::init();
+        return None;
+    }
+
+    // `log` records of every module are bridged into tracing, so they get the job span too
+    let fmt = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => json_layer(std::io::stdout),
+    };
+    let provider = otel_endpoint.map(otel::init).transpose();
+    let (provider, otel_err) = match provider {
+        Ok(provider) => (provider, None),
+        Err(err) => (None, Some(err)),
+    };
+    tracing_subscriber::registry()
+        .with(fmt.with_filter(EnvFilter::from_default_env()))
+        .with(provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+                .with_filter(LevelFilter::INFO)
+        }))
+        .init();
+
+    if let Some(err) = otel_err {
+        error!("OpenTelemetry exporter is disabled ({err})");
+    }
+    provider
+}
+
+/// One json object per line, fields of the span the line is logged in are under `span`.
+/// Only that span is written, so every span a job goes through must carry the job fields.
+pub(crate) fn json_layer<S, W>(make_writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_writer(make_writer)
+        .boxed()
+}
@@ -1,11 +1,23 @@
+mod assignment;
+mod auth;
 mod custom_error;
+mod device_registry;
 mod file_handler;
 mod httpserver;
+mod job_board;
 mod jobs;
+mod logging;
 mod messenger;
+mod metrics;
+mod otel;
 mod settings;
 mod telemetry;
+mod tls;
+mod topic;
+mod webhook;
 
+use clap::Parser;
+use std::process::ExitCode;
 use std::sync::mpsc;
 
 // pretty_env_logger related
@@ -13,22 +25,59 @@ extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
-fn main() {
-    pretty_env_logger::init();
+fn main() -> ExitCode {
+    let cli = settings::Cli::parse();
+    let settings = settings::init(&cli);
+    // Invalid settings are still reported, in the default format
+    let tracer_provider = match &settings {
+        Ok(settings) => logging::init(settings.log_format, settings.otel_endpoint.as_deref()),
+        Err(_) => logging::init(Default::default(), None),
+    };
     info!("Starting fota service");
+    if let Err(err) = settings {
+        error!("{err}");
+        return ExitCode::FAILURE;
+    }
 
     // Create channel for passing notification from messenger to jobs thread
     let (tx_notification, rx_notification) = mpsc::channel();
+    // Create channel for passing broker acknowledgement of publish from messenger to jobs thread
+    let (tx_delivery, rx_delivery) = mpsc::channel();
     // Create channel for passing new job from http server to jobs thread
     let (tx_new_job, rx_new_job) = mpsc::channel();
 
     // Initialize messenger, it already handle mqtt connection on other thread
-    let messenger = messenger::Messenger::new(tx_notification);
+    let messenger = messenger::Messenger::new(tx_notification, tx_delivery);
+
+    // Post job status change to webhooks from its own thread
+    let webhooks = webhook::Webhooks::start();
+    // Job status board shared between http server and jobs thread
+    let board = job_board::JobBoard::new(webhooks.clone());
+    // Device registry shared between http server and jobs thread
+    let registry = device_registry::DeviceRegistry::default();
+    // Firmware assigned to hardware model, for device asking for update
+    let assignments = assignment::Assignments::default();
 
     // Initialize jobs and run
-    let jobs = jobs::JobScheduler::new(messenger, rx_notification, rx_new_job);
+    let jobs = jobs::JobScheduler::new(
+        messenger,
+        rx_notification,
+        rx_delivery,
+        rx_new_job,
+        board.clone(),
+        registry.clone(),
+        assignments.clone(),
+    );
     jobs.run();
 
-    let mut http = httpserver::HTTPServer::new(tx_new_job);
+    let http = httpserver::HTTPServer::new(tx_new_job, board, registry, assignments, webhooks);
     http.run();
+
+    // Flush spans still waiting in the exporter batch
+    if let Some(provider) = tracer_provider {
+        if let Err(err) = provider.shutdown() {
+            error!("OpenTelemetry exporter shutdown failed ({err})");
+        }
+    }
+    ExitCode::SUCCESS
 }
@@ -1,71 +1,624 @@
-use rumqttc::{Client, Connection, Event, MqttOptions, QoS};
+use bytes::Bytes;
+use rumqttc::v5;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Outgoing, QoS, Transport};
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use crate::settings::settings;
-use crate::telemetry::Telemetry;
+use crate::metrics::metrics;
+use crate::settings::{settings, MqttProtocol};
+use crate::telemetry::{self, Qos, Telemetry};
+use crate::tls::MqttTls;
+use crate::topic::{topics, TopicTemplate, Topics};
+
+/// Version of rocky fota protocol, sent as user property on every mqtt v5 publish
+const PROTOCOL_VERSION: &str = "1";
+
+enum MqttClient {
+    V4(Client),
+    V5(v5::Client),
+}
+
+/// Whether the broker connection is up, shared between the connection thread and its users
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionState(Arc<AtomicBool>);
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_connected(&self, connected: bool) {
+        self.0.store(connected, Ordering::Release);
+    }
+}
+
+/// Identifier of a publish, reported back once the broker has it
+pub type DeliveryId = u64;
+
+/// Match every publish to its broker acknowledgement. Rumqttc doesn't tell the packet id of a
+/// publish, but outgoing publish events come in the same order as the publish calls.
+/// Publish forgotten on disconnect keeps its place with no id, so it is matched but never reported.
+#[derive(Debug, Default)]
+struct Deliveries {
+    next_id: DeliveryId,
+    /// Published but not written to the broker yet
+    queued: VecDeque<(Option<DeliveryId>, Qos)>,
+    /// Written to the broker, waiting for PUBACK (QoS 1) or PUBCOMP (QoS 2)
+    inflight: HashMap<u16, Option<DeliveryId>>,
+}
+
+impl Deliveries {
+    fn queue(&mut self, qos: Qos) -> DeliveryId {
+        self.next_id += 1;
+        self.queued.push_back((Some(self.next_id), qos));
+        self.next_id
+    }
+
+    /// Publish is written out, QoS 0 is delivered as far as anyone will ever know
+    fn outgoing(&mut self, pkid: u16) -> Option<DeliveryId> {
+        // Retransmit after reconnect keeps its packet id, it isn't a new publish
+        if self.inflight.contains_key(&pkid) {
+            return None;
+        }
+        let (id, qos) = self.queued.pop_front()?;
+        if qos == Qos::AtMostOnce {
+            return id;
+        }
+        self.inflight.insert(pkid, id);
+        None
+    }
+
+    fn acknowledged(&mut self, pkid: u16) -> Option<DeliveryId> {
+        self.inflight.remove(&pkid).flatten()
+    }
+
+    /// Forget every publish still waiting, the jobs thread publishes them again after reconnect.
+    /// Rumqttc still replays them, so they stay in place to keep the order.
+    fn reset(&mut self) {
+        self.queued.iter_mut().for_each(|(id, _)| *id = None);
+        self.inflight.values_mut().for_each(|id| *id = None);
+    }
+}
+
+/// Everything the connection thread shares with the messenger
+#[derive(Clone)]
+struct Link {
+    state: ConnectionState,
+    deliveries: Arc<Mutex<Deliveries>>,
+    tx_notification: mpsc::Sender<Telemetry>,
+    tx_delivery: mpsc::Sender<DeliveryId>,
+}
+
+impl Link {
+    fn outgoing(&self, pkid: u16) {
+        let delivered = self.deliveries.lock().unwrap().outgoing(pkid);
+        if let Some(id) = delivered {
+            _ = self.tx_delivery.send(id);
+        }
+    }
+
+    fn acknowledged(&self, pkid: u16) {
+        let delivered = self.deliveries.lock().unwrap().acknowledged(pkid);
+        if let Some(id) = delivered {
+            _ = self.tx_delivery.send(id);
+        }
+    }
+}
+
+/// Exponential delay between reconnect attempts, doubled on every failure up to `max`
+#[derive(Debug)]
+struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    fn next(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
 
 pub struct Messenger {
-    mqttc: Client,
+    mqttc: MqttClient,
+    link: Link,
 }
 
 impl Messenger {
-    pub fn new(tx_notification: mpsc::Sender<Telemetry>) -> Self {
-        // Configure mqtt connection
+    pub fn new(
+        tx_notification: mpsc::Sender<Telemetry>,
+        tx_delivery: mpsc::Sender<DeliveryId>,
+    ) -> Self {
+        // Configure mqtt connection, the same for both protocol version
+        let credentials = settings()
+            .mqtt_credentials()
+            .unwrap_or_else(|err| panic!("Invalid mqtt credentials ({err})"));
+        let transport = settings().mqtt_tls.then(|| {
+            let tls = MqttTls {
+                ca: settings().mqtt_tls_ca.clone(),
+                client_cert: settings().mqtt_tls_client_cert.clone(),
+                client_key: settings().mqtt_tls_client_key.clone(),
+                alpn: settings().mqtt_tls_alpn.clone(),
+            };
+            info!("Mqtt connection use tls");
+            tls.transport()
+                .unwrap_or_else(|err| panic!("Invalid mqtt tls configuration ({err})"))
+        });
+
+        let link = Link {
+            state: ConnectionState::default(),
+            deliveries: Arc::default(),
+            tx_notification,
+            tx_delivery,
+        };
+        let mqttc = match settings().mqtt_protocol {
+            MqttProtocol::V4 => Self::connect_v4(credentials, transport, link.clone()),
+            MqttProtocol::V5 => Self::connect_v5(credentials, transport, link.clone()),
+        };
+
+        info!("Messenger is running with connection handler in its own thread!");
+        Self { mqttc, link }
+    }
+
+    /// Connected messenger whose connection is never polled, what it publishes stays in
+    /// the returned connection
+    #[cfg(test)]
+    pub fn unpolled(
+        tx_notification: mpsc::Sender<Telemetry>,
+        tx_delivery: mpsc::Sender<DeliveryId>,
+    ) -> (Self, Connection) {
+        let options = MqttOptions::new("rocky-test", "localhost", 1883);
+        let (client, connection) = Client::new(options, settings().mqtt_request_capacity);
+        let link = Link {
+            state: ConnectionState::default(),
+            deliveries: Arc::default(),
+            tx_notification,
+            tx_delivery,
+        };
+        link.state.set_connected(true);
+        let mqttc = MqttClient::V4(client);
+        (Self { mqttc, link }, connection)
+    }
+
+    /// Handle to watch the broker connection, e.g. to pause publishing while it is down
+    pub fn connection_state(&self) -> ConnectionState {
+        self.link.state.clone()
+    }
+
+    fn backoff() -> Backoff {
+        Backoff::new(
+            Duration::from_millis(settings().mqtt_reconnect_min_ms),
+            Duration::from_millis(settings().mqtt_reconnect_max_ms),
+        )
+    }
+
+    fn connect_v4(
+        credentials: Option<(String, String)>,
+        transport: Option<Transport>,
+        link: Link,
+    ) -> MqttClient {
         let mut mqtt_options = MqttOptions::new(
             settings().mqtt_client_id.clone(),
             settings().mqtt_host.clone(),
             settings().mqtt_port,
         );
         mqtt_options.set_keep_alive(Duration::from_secs(5));
-        // TODO: Add last will if necessary later
+        if let Some((username, password)) = credentials {
+            mqtt_options.set_credentials(username, password);
+        }
+        if let Some(transport) = transport {
+            mqtt_options.set_transport(transport);
+        }
+        // Broker announces rocky offline when the connection is lost without a goodbye
+        let offline = telemetry::build_status(false);
+        mqtt_options.set_last_will(LastWill::new(
+            offline.topic,
+            offline.payload,
+            Self::qos(offline.qos),
+            offline.retain,
+        ));
 
-        // Initiate mqtt connection and run Connection handler on different thread
-        let (client, connection) = Client::new(mqtt_options, 1);
-        thread::spawn(move || Messenger::run_connection(connection, tx_notification));
+        // Initiate mqtt connection and run Connection handler on different thread,
+        // it subscribes to command response topic on every (re)connect
+        let (client, connection) = Client::new(mqtt_options, settings().mqtt_request_capacity);
+        let subscriber = client.clone();
+        thread::spawn(move || Messenger::run_connection(connection, subscriber, link));
+        MqttClient::V4(client)
+    }
 
-        // Subscribe to command response topic
-        client
-            .subscribe("/fota/cmd_resp/+", QoS::AtMostOnce) // TODO: Topic from other const
-            .unwrap(); // TODO: Handle return result
+    fn connect_v5(
+        credentials: Option<(String, String)>,
+        transport: Option<Transport>,
+        link: Link,
+    ) -> MqttClient {
+        let mut mqtt_options = v5::MqttOptions::new(
+            settings().mqtt_client_id.clone(),
+            settings().mqtt_host.clone(),
+            settings().mqtt_port,
+        );
+        mqtt_options.set_keep_alive(Duration::from_secs(5));
+        if let Some((username, password)) = credentials {
+            mqtt_options.set_credentials(username, password);
+        }
+        if let Some(transport) = transport {
+            mqtt_options.set_transport(transport);
+        }
+        let offline = telemetry::build_status(false);
+        mqtt_options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+            offline.topic,
+            offline.payload,
+            Self::qos_v5(offline.qos),
+            offline.retain,
+            None,
+        ));
 
-        info!("Messenger is running with connection handler in its own thread!");
-        Self { mqttc: client }
+        let (client, connection) = v5::Client::new(mqtt_options, settings().mqtt_request_capacity);
+        let subscriber = client.clone();
+        thread::spawn(move || Messenger::run_connection_v5(connection, subscriber, link));
+        info!("Mqtt connection use protocol v5");
+        MqttClient::V5(client)
     }
 
-    pub fn send(&mut self, telemetry: Telemetry) -> Result<(), Box<dyn Error>> {
-        let result =
-            self.mqttc
-                .publish(telemetry.topic, QoS::AtLeastOnce, false, telemetry.payload)?;
-        Ok(result)
+    /// Publish to the broker, its id is reported on the delivery channel once the broker has it.
+    /// Refused while disconnected or when the request channel is full, so the caller keeps the message.
+    pub fn send(&mut self, telemetry: Telemetry) -> Result<DeliveryId, Box<dyn Error>> {
+        if !self.link.state.is_connected() {
+            return Err("mqtt broker is not connected".into());
+        }
+        // Hold the lock until queued, so the connection thread sees publish in the same order
+        let mut deliveries = self.link.deliveries.lock().unwrap();
+        let qos = telemetry.qos;
+        match &self.mqttc {
+            MqttClient::V4(client) => client.try_publish(
+                telemetry.topic,
+                Self::qos(qos),
+                telemetry.retain,
+                telemetry.payload,
+            )?,
+            MqttClient::V5(client) => {
+                let properties = Self::publish_properties(&telemetry);
+                client.try_publish_with_properties(
+                    telemetry.topic,
+                    Self::qos_v5(qos),
+                    telemetry.retain,
+                    telemetry.payload,
+                    properties,
+                )?
+            }
+        }
+        Ok(deliveries.queue(qos))
+    }
+
+    fn qos(qos: Qos) -> QoS {
+        match qos {
+            Qos::AtMostOnce => QoS::AtMostOnce,
+            Qos::AtLeastOnce => QoS::AtLeastOnce,
+            Qos::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+
+    fn qos_v5(qos: Qos) -> v5::mqttbytes::QoS {
+        match qos {
+            Qos::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+            Qos::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+            Qos::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+        }
     }
 
-    fn run_connection(mut connection: Connection, tx_notification: mpsc::Sender<Telemetry>) {
+    fn publish_properties(telemetry: &Telemetry) -> PublishProperties {
+        PublishProperties {
+            message_expiry_interval: telemetry.message_expiry_secs,
+            response_topic: telemetry.response_topic.clone(),
+            correlation_data: telemetry.correlation_data.clone().map(Bytes::from),
+            user_properties: vec![(
+                String::from("rocky-protocol"),
+                String::from(PROTOCOL_VERSION),
+            )],
+            ..Default::default()
+        }
+    }
+
+    /// Topic filters subscribed on every connect. Shared subscription lets several rocky
+    /// instances split the update checks. Command responses are never shared, only the
+    /// instance that sent the command knows its transfer token, it has its own response
+    /// topic instead. Presence is needed in full by every instance.
+    fn subscription_filters(topics: &Topics, shared_group: Option<&str>) -> Vec<String> {
+        let shared = |filter: String| match shared_group {
+            Some(group) => format!("$share/{group}/{filter}"),
+            None => filter,
+        };
+        let mut filters = vec![topics.cmd_resp.subscription()];
+        filters.extend(
+            topics
+                .check
+                .as_ref()
+                .map(|check| shared(check.subscription())),
+        );
+        filters.extend(topics.presence.as_ref().map(TopicTemplate::subscription));
+        filters
+    }
+
+    fn run_connection(mut connection: Connection, client: Client, link: Link) {
+        let mut backoff = Self::backoff();
         for notification in connection.iter() {
             match notification {
-                Ok(event) => Messenger::handle_notification_event(event, &tx_notification),
-                Err(error) => warn!("Event messaging error {error}"), // TODO: Need better handling
+                Ok(Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                    // Clean session forgets subscription, so subscribe again on every connect.
+                    // Nothing else publish while disconnected, so request channel has room for it.
+                    for filter in Self::subscription_filters(topics(), None) {
+                        if let Err(err) =
+                            client.try_subscribe(&filter, Self::qos(settings().mqtt_qos_response))
+                        {
+                            error!("Subscribe {filter} failed ({err})");
+                        }
+                    }
+                    // Replace the retained offline last will of the previous connection
+                    let online = telemetry::build_status(true);
+                    let mut deliveries = link.deliveries.lock().unwrap();
+                    match client.try_publish(
+                        online.topic,
+                        Self::qos(online.qos),
+                        online.retain,
+                        online.payload,
+                    ) {
+                        Ok(()) => _ = deliveries.queue(online.qos),
+                        Err(err) => error!("Publish online status failed ({err})"),
+                    }
+                    drop(deliveries);
+                    Self::connected(&link.state, &mut backoff);
+                }
+                Ok(event) => Messenger::handle_notification_event(event, &link),
+                Err(error) => Self::disconnected(&link, &mut backoff, error),
             }
         }
     }
 
-    fn handle_notification_event(event: Event, notif: &mpsc::Sender<Telemetry>) {
-        let Event::Incoming(incoming) = event else {
-            return;
+    fn run_connection_v5(mut connection: v5::Connection, client: v5::Client, link: Link) {
+        let filters =
+            Self::subscription_filters(topics(), settings().mqtt_v5_shared_group.as_deref());
+        let mut backoff = Self::backoff();
+        for notification in connection.iter() {
+            match notification {
+                Ok(v5::Event::Incoming(v5::mqttbytes::v5::Packet::ConnAck(_))) => {
+                    for filter in &filters {
+                        if let Err(err) =
+                            client.try_subscribe(filter, Self::qos_v5(settings().mqtt_qos_response))
+                        {
+                            error!("Subscribe {filter} failed ({err})");
+                        }
+                    }
+                    let online = telemetry::build_status(true);
+                    let properties = Self::publish_properties(&online);
+                    let mut deliveries = link.deliveries.lock().unwrap();
+                    match client.try_publish_with_properties(
+                        online.topic,
+                        Self::qos_v5(online.qos),
+                        online.retain,
+                        online.payload,
+                        properties,
+                    ) {
+                        Ok(()) => _ = deliveries.queue(online.qos),
+                        Err(err) => error!("Publish online status failed ({err})"),
+                    }
+                    drop(deliveries);
+                    Self::connected(&link.state, &mut backoff);
+                }
+                Ok(event) => Messenger::handle_notification_event_v5(event, &link),
+                Err(error) => Self::disconnected(&link, &mut backoff, error),
+            }
+        }
+    }
+
+    fn connected(state: &ConnectionState, backoff: &mut Backoff) {
+        info!("Mqtt broker connected");
+        state.set_connected(true);
+        backoff.reset();
+    }
+
+    /// Connection iterator reconnects on the next poll, wait a bit so a down broker isn't hammered
+    fn disconnected(link: &Link, backoff: &mut Backoff, error: impl std::fmt::Display) {
+        if link.state.is_connected() {
+            warn!("Mqtt broker disconnected ({error})");
+            metrics().mqtt_disconnects.inc();
+            link.deliveries.lock().unwrap().reset();
+        }
+        link.state.set_connected(false);
+        let delay = backoff.next();
+        warn!("Mqtt connection error ({error}), retry in {delay:?}");
+        thread::sleep(delay);
+    }
+
+    fn handle_notification_event(event: Event, link: &Link) {
+        let incoming = match event {
+            Event::Incoming(incoming) => incoming,
+            Event::Outgoing(Outgoing::Publish(pkid)) => return link.outgoing(pkid),
+            Event::Outgoing(_) => return,
         };
 
         match incoming {
+            rumqttc::Packet::PubAck(ack) => link.acknowledged(ack.pkid),
+            rumqttc::Packet::PubComp(comp) => link.acknowledged(comp.pkid),
             rumqttc::Packet::Publish(data) => {
                 let notification = Telemetry {
                     topic: data.topic,
                     payload: data.payload.to_vec(),
+                    ..Default::default()
+                };
+                debug!("Incoming publish data: {:?}", notification);
+                _ = link.tx_notification.send(notification);
+            }
+            other => debug!("Incoming event {other:?}"),
+        }
+    }
+
+    fn handle_notification_event_v5(event: v5::Event, link: &Link) {
+        let incoming = match event {
+            v5::Event::Incoming(incoming) => incoming,
+            v5::Event::Outgoing(Outgoing::Publish(pkid)) => return link.outgoing(pkid),
+            v5::Event::Outgoing(_) => return,
+        };
+
+        match incoming {
+            v5::mqttbytes::v5::Packet::PubAck(ack) => link.acknowledged(ack.pkid),
+            v5::mqttbytes::v5::Packet::PubComp(comp) => link.acknowledged(comp.pkid),
+            v5::mqttbytes::v5::Packet::Publish(data) => {
+                let notification = Telemetry {
+                    topic: String::from_utf8_lossy(&data.topic).into_owned(),
+                    payload: data.payload.to_vec(),
+                    correlation_data: data
+                        .properties
+                        .and_then(|properties| properties.correlation_data)
+                        .map(|data| data.to_vec()),
+                    ..Default::default()
                 };
                 debug!("Incoming publish data: {:?}", notification);
-                _ = notif.send(notification);
+                _ = link.tx_notification.send(notification);
             }
             other => debug!("Incoming event {other:?}"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{Cli, Settings};
+    use crate::telemetry::{build_command, CommandType};
+
+    /// Topics of one instance sharing the `rocky` group
+    fn instance_topics(client_id: &str) -> Topics {
+        let mut settings = Settings::load(&Cli::default()).unwrap();
+        settings.mqtt_client_id = client_id.to_string();
+        settings.mqtt_topic_cmd_resp = String::from("/fota/cmd_resp/{instance_id}/{device_id}");
+        settings.mqtt_topic_check = Some(String::from("/fota/check/{device_id}"));
+        Topics::from_settings(&settings).unwrap()
+    }
+
+    /// Instance gets a publish when one of its filters matches, shared filter included
+    fn receives(filters: &[String], topic: &str) -> bool {
+        filters.iter().any(|filter| {
+            let filter = match filter.strip_prefix("$share/rocky/") {
+                Some(filter) => filter,
+                None => filter,
+            };
+            rumqttc::matches(topic, filter)
+        })
+    }
+
+    #[test]
+    fn test_shared_group_keeps_command_responses_per_instance() {
+        let (a, b) = (instance_topics("rocky-a"), instance_topics("rocky-b"));
+        let filters_a = Messenger::subscription_filters(&a, Some("rocky"));
+        let filters_b = Messenger::subscription_filters(&b, Some("rocky"));
+        assert!(filters_a.contains(&String::from("$share/rocky//fota/check/+")));
+        assert!(filters_b.contains(&String::from("$share/rocky//fota/check/+")));
+
+        // Device answers on the response topic of the command, only its sender gets it
+        for (own, own_filters, other_filters) in
+            [(&a, &filters_a, &filters_b), (&b, &filters_b, &filters_a)]
+        {
+            let response = own.cmd_resp.render("musang");
+            assert!(receives(own_filters, &response));
+            assert!(!receives(other_filters, &response));
+            assert_eq!(own.cmd_resp.device_id(&response), Some("musang"));
+        }
+    }
+
+    #[test]
+    fn test_deliveries_follow_publish_order() {
+        let mut deliveries = Deliveries::default();
+        let first = deliveries.queue(Qos::AtLeastOnce);
+        let second = deliveries.queue(Qos::AtMostOnce);
+        let third = deliveries.queue(Qos::ExactlyOnce);
+
+        assert_eq!(deliveries.outgoing(1), None);
+        assert_eq!(deliveries.outgoing(0), Some(second));
+        assert_eq!(deliveries.outgoing(2), None);
+        // Retransmit after reconnect doesn't take the next queued publish
+        assert_eq!(deliveries.outgoing(1), None);
+
+        assert_eq!(deliveries.acknowledged(2), Some(third));
+        assert_eq!(deliveries.acknowledged(1), Some(first));
+        assert_eq!(deliveries.acknowledged(1), None);
+    }
+
+    #[test]
+    fn test_deliveries_reset_forgets_outstanding() {
+        let mut deliveries = Deliveries::default();
+        deliveries.queue(Qos::AtLeastOnce);
+        assert_eq!(deliveries.outgoing(1), None);
+        deliveries.queue(Qos::AtMostOnce);
+        deliveries.queue(Qos::AtLeastOnce);
+
+        deliveries.reset();
+        // Replayed by rumqttc after reconnect, matched but not reported
+        assert_eq!(deliveries.outgoing(1), None);
+        assert_eq!(deliveries.outgoing(0), None);
+        assert_eq!(deliveries.outgoing(2), None);
+        assert_eq!(deliveries.acknowledged(1), None);
+        assert_eq!(deliveries.acknowledged(2), None);
+
+        let after = deliveries.queue(Qos::AtLeastOnce);
+        assert_eq!(deliveries.outgoing(3), None);
+        assert_eq!(deliveries.acknowledged(3), Some(after));
+        assert!(deliveries.queued.is_empty());
+        assert!(deliveries.inflight.is_empty());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(350));
+        assert_eq!(backoff.next(), Duration::from_millis(100));
+        assert_eq!(backoff.next(), Duration::from_millis(200));
+        assert_eq!(backoff.next(), Duration::from_millis(350));
+        assert_eq!(backoff.next(), Duration::from_millis(350));
+
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_command_properties() {
+        let tlm = build_command(
+            42,
+            "musang",
+            CommandType::OtaRequest,
+            &[0u8; 32],
+            Qos::AtLeastOnce,
+        )
+        .unwrap();
+        let properties = Messenger::publish_properties(&tlm);
+
+        assert_eq!(
+            properties.response_topic,
+            Some(topics().cmd_resp.render("musang"))
+        );
+        assert_eq!(
+            properties.correlation_data,
+            Some(Bytes::from(42u32.to_be_bytes().to_vec()))
+        );
+        assert_eq!(
+            properties.user_properties,
+            [(String::from("rocky-protocol"), String::from("1"))]
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+
+use crate::jobs::JobStatus;
+use crate::settings::settings;
+
+/// Every metric exposed on `/metrics`, updated from messenger, jobs and http threads
+pub struct Metrics {
+    registry: Registry,
+    /// Jobs on the board by status, refreshed on every scrape
+    pub jobs: IntGaugeVec,
+    pub jobs_queued: IntGauge,
+    pub jobs_running: IntGauge,
+    pub jobs_max_running: IntGauge,
+    pub chunks_published: IntCounter,
+    pub bytes_published: IntCounter,
+    pub mqtt_disconnects: IntCounter,
+    pub download_seconds: Histogram,
+    pub download_bytes: Histogram,
+    /// From `FOTA_REQUEST` sent to `FOTA_REQUEST_ACK` received
+    pub handshake_seconds: Histogram,
+    /// How late a chunk is sent compared to `job_processed_interval_ms`
+    pub interval_missed_seconds: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let seconds = exponential_buckets(0.005, 2.0, 14)?;
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some(String::from("rocky")), None)?,
+            jobs: IntGaugeVec::new(
+                Opts::new("jobs", "Jobs on the board by status"),
+                &["status"],
+            )?,
+            jobs_queued: IntGauge::new("jobs_queued", "Jobs waiting on the queue")?,
+            jobs_running: IntGauge::new("jobs_running", "Jobs sending chunks")?,
+            jobs_max_running: IntGauge::new("jobs_max_running", "Configured job_max_running")?,
+            chunks_published: IntCounter::new(
+                "chunks_published_total",
+                "Image chunks handed to the mqtt client",
+            )?,
+            bytes_published: IntCounter::new(
+                "bytes_published_total",
+                "Image bytes handed to the mqtt client",
+            )?,
+            mqtt_disconnects: IntCounter::new("mqtt_disconnects_total", "Broker connection lost")?,
+            download_seconds: Histogram::with_opts(
+                HistogramOpts::new("download_seconds", "Firmware binary download latency")
+                    .buckets(seconds.clone()),
+            )?,
+            download_bytes: Histogram::with_opts(
+                HistogramOpts::new("download_bytes", "Firmware binary size")
+                    .buckets(exponential_buckets(1024.0, 4.0, 10)?),
+            )?,
+            handshake_seconds: Histogram::with_opts(
+                HistogramOpts::new("handshake_seconds", "Fota request until the device accepts")
+                    .buckets(seconds.clone()),
+            )?,
+            interval_missed_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "interval_missed_seconds",
+                    "Delay of a chunk past job_processed_interval_ms",
+                )
+                .buckets(seconds),
+            )?,
+        };
+
+        metrics.register(Box::new(metrics.jobs.clone()))?;
+        metrics.register(Box::new(metrics.jobs_queued.clone()))?;
+        metrics.register(Box::new(metrics.jobs_running.clone()))?;
+        metrics.register(Box::new(metrics.jobs_max_running.clone()))?;
+        metrics.register(Box::new(metrics.chunks_published.clone()))?;
+        metrics.register(Box::new(metrics.bytes_published.clone()))?;
+        metrics.register(Box::new(metrics.mqtt_disconnects.clone()))?;
+        metrics.register(Box::new(metrics.download_seconds.clone()))?;
+        metrics.register(Box::new(metrics.download_bytes.clone()))?;
+        metrics.register(Box::new(metrics.handshake_seconds.clone()))?;
+        metrics.register(Box::new(metrics.interval_missed_seconds.clone()))?;
+        metrics
+            .jobs_max_running
+            .set(settings().job_max_running.into());
+        Ok(metrics)
+    }
+
+    fn register(&self, collector: Box<dyn prometheus::core::Collector>) -> prometheus::Result<()> {
+        self.registry.register(collector)
+    }
+
+    /// Set the jobs gauge, status without any job is reported as 0.
+    /// Counted before anything is set, so concurrent scrapes never see a partial count.
+    pub fn set_jobs(&self, statuses: impl IntoIterator<Item = JobStatus>) {
+        let statuses: Vec<JobStatus> = statuses.into_iter().collect();
+        for status in JobStatus::ALL {
+            let count = statuses.iter().filter(|job| **job == status).count();
+            self.jobs
+                .with_label_values(&[status.as_str()])
+                .set(count as i64);
+        }
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Encode metrics failed ({err})");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+static M: OnceLock<Metrics> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics {
+    // Metric names are fixed, failing here is a programming error
+    M.get_or_init(|| Metrics::new().unwrap_or_else(|err| panic!("{err}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new().unwrap();
+        metrics.set_jobs([JobStatus::OnQueue, JobStatus::OnQueue, JobStatus::Success]);
+        metrics.chunks_published.inc_by(3);
+        metrics.download_bytes.observe(2048.0);
+
+        let text = metrics.render();
+        assert!(text.contains("rocky_jobs{status=\"on_queue\"} 2"));
+        assert!(text.contains("rocky_jobs{status=\"failed\"} 0"));
+        assert!(text.contains("rocky_chunks_published_total 3"));
+        assert!(text.contains("rocky_download_bytes_count 1"));
+
+        // Set again, not added on top of the previous scrape
+        metrics.set_jobs([JobStatus::OnQueue]);
+        assert!(metrics
+            .render()
+            .contains("rocky_jobs{status=\"on_queue\"} 1"));
+    }
+}
@@ -1,32 +1,548 @@
-use config::{Config, ConfigError, File};
+use clap::Parser;
+use config::builder::DefaultState;
+use config::{Config, ConfigBuilder, Environment, File, FileFormat, Map};
 use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
 use std::sync::OnceLock;
 
+use crate::auth::ApiToken;
+use crate::custom_error::SettingsError;
+use crate::telemetry::Qos;
+use crate::topic::{self, Topics};
+use crate::webhook;
+
+/// Command line of rocky. Settings are layered from defaults, then the config file,
+/// then `ROCKY_*` environment variables and finally `--set` from the command line.
+#[derive(Debug, Default, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Configuration file [default: rocky.toml, skipped when missing]
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Override a setting, value is parsed as toml and taken as string otherwise.
+    /// e.g. `--set mqtt_port=8883 --set 'mqtt_tls_alpn=["mqtt"]'`
+    #[arg(short = 's', long = "set", value_name = "KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+}
+
+fn parse_override(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected KEY=VALUE, got `{arg}`")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttProtocol {
+    V4,
+    V5,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable line from `pretty_env_logger`
+    #[default]
+    Text,
+    /// One json object per line, job events carry `job_id` and `device_id`
+    Json,
+}
+
+/// What happens to a new job when its device already has one that isn't finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// New job is refused
+    Reject,
+    /// Job still on queue is failed in favor of the new one, started job can't be replaced
+    Replace,
+    /// New job waits until the device is done with the previous one
+    Queue,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct Settings {
+    pub log_format: LogFormat,
+    /// OTLP/HTTP traces url of the collector, e.g. `http://localhost:4318/v1/traces`
+    pub otel_endpoint: Option<String>,
+    pub otel_service_name: String,
+    /// Notified on every job status change, on top of the job `callback_url`
+    #[serde(default)]
+    pub webhook_urls: Vec<String>,
+    /// Host a job `callback_url` may point to, callback url is refused when empty
+    #[serde(default)]
+    pub webhook_callback_hosts: Vec<String>,
+    /// Payload is signed with HMAC-SHA256 when set
+    pub webhook_secret: Option<String>,
+    pub webhook_max_attempts: u32,
+    pub webhook_retry_ms: u64,
+    /// Retry delay stops doubling at this
+    pub webhook_retry_max_ms: u64,
+    pub webhook_timeout_ms: u64,
+    /// Events waiting to be posted, per url, newer event is dropped when it is full
+    pub webhook_queue_size: usize,
     pub job_max_running: u8,
     pub job_processed_interval_ms: u64,
+    pub job_max_send_failures: u32,
+    pub job_ack_timeout_ms: u64,
+    pub job_history_max: usize,
+    pub job_conflict_policy: ConflictPolicy,
     pub chunk_size_per_transmission: u16,
     pub http_host: String,
-    pub http_port: u32,
+    pub http_port: u16,
+    pub http_max_body_bytes: usize,
+    pub http_tls_cert: Option<String>,
+    pub http_tls_key: Option<String>,
+    pub http_tls_client_ca: Option<String>,
+    #[serde(default)]
+    pub api_tokens: Vec<ApiToken>,
+    pub api_token_file: Option<String>,
+    /// Serve the http api without any token, rocky refuses to start without token otherwise
+    pub http_auth_disabled: bool,
     pub mqtt_client_id: String,
     pub mqtt_host: String,
     pub mqtt_port: u16,
+    pub mqtt_protocol: MqttProtocol,
+    pub mqtt_qos_command: Qos,
+    pub mqtt_qos_data: Qos,
+    pub mqtt_qos_response: Qos,
+    pub mqtt_request_capacity: usize,
+    pub mqtt_reconnect_min_ms: u64,
+    pub mqtt_reconnect_max_ms: u64,
+    pub mqtt_v5_shared_group: Option<String>,
+    pub mqtt_v5_chunk_expiry_secs: Option<u32>,
+    pub mqtt_tenant: Option<String>,
+    pub mqtt_topic_cmd: String,
+    pub mqtt_topic_cmd_resp: String,
+    pub mqtt_topic_data: String,
+    pub mqtt_topic_status: String,
+    pub mqtt_topic_presence: Option<String>,
+    pub mqtt_topic_check: Option<String>,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    pub mqtt_password_file: Option<String>,
+    #[serde(default)]
+    pub mqtt_tls: bool,
+    pub mqtt_tls_ca: Option<String>,
+    pub mqtt_tls_client_cert: Option<String>,
+    pub mqtt_tls_client_key: Option<String>,
+    #[serde(default)]
+    pub mqtt_tls_alpn: Vec<String>,
 }
 
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
-        let s = Config::builder()
-            .add_source(File::with_name("rocky.toml"))
-            .build()
-            .unwrap();
+    pub fn load(cli: &Cli) -> Result<Self, SettingsError> {
+        Self::load_with_env(cli, None)
+    }
+
+    /// Same as `load`, `ROCKY_*` variables are taken from `env` instead of the process when given
+    fn load_with_env(cli: &Cli, env: Option<Map<String, String>>) -> Result<Self, SettingsError> {
+        // File is only required when it is given explicitly
+        let file = match &cli.config {
+            Some(path) => File::from(path.as_path()),
+            None => File::with_name("rocky.toml").required(false),
+        };
 
-        s.try_deserialize()
+        let mut builder = Self::defaults(Config::builder())?
+            .add_source(file)
+            .add_source(
+                Environment::with_prefix("ROCKY")
+                    .try_parsing(true)
+                    .list_separator(",")
+                    .with_list_parse_key("mqtt_tls_alpn")
+                    .with_list_parse_key("webhook_urls")
+                    .with_list_parse_key("webhook_callback_hosts")
+                    .source(env),
+            );
+        for (key, value) in &cli.overrides {
+            builder = Self::override_from_cli(builder, key, value)?;
+        }
+
+        let settings: Self = builder.build()?.try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn defaults(
+        builder: ConfigBuilder<DefaultState>,
+    ) -> Result<ConfigBuilder<DefaultState>, SettingsError> {
+        Ok(builder
+            .set_default("job_max_running", 3)?
+            .set_default("log_format", "text")?
+            .set_default("otel_service_name", "rocky")?
+            .set_default("webhook_max_attempts", 5)?
+            .set_default("webhook_retry_ms", 1000)?
+            .set_default("webhook_retry_max_ms", 60000)?
+            .set_default("webhook_timeout_ms", 10000)?
+            .set_default("webhook_queue_size", 1000)?
+            .set_default("job_processed_interval_ms", 200)?
+            .set_default("job_max_send_failures", 5)?
+            .set_default("job_ack_timeout_ms", 10000)?
+            .set_default("job_history_max", 10000)?
+            .set_default("job_conflict_policy", "queue")?
+            .set_default("chunk_size_per_transmission", 5)?
+            .set_default("http_host", "127.0.0.1")?
+            .set_default("http_port", 7777)?
+            .set_default("http_max_body_bytes", 16384)?
+            .set_default("http_auth_disabled", false)?
+            .set_default("mqtt_client_id", "rocky")?
+            .set_default("mqtt_host", "localhost")?
+            .set_default("mqtt_port", 1883)?
+            .set_default("mqtt_protocol", "v4")?
+            .set_default("mqtt_qos_command", 1)?
+            .set_default("mqtt_qos_data", 1)?
+            .set_default("mqtt_qos_response", 1)?
+            .set_default("mqtt_request_capacity", 64)?
+            .set_default("mqtt_reconnect_min_ms", 500)?
+            .set_default("mqtt_reconnect_max_ms", 30000)?
+            .set_default("mqtt_topic_cmd", "/fota/cmd/{device_id}")?
+            .set_default("mqtt_topic_cmd_resp", "/fota/cmd_resp/{device_id}")?
+            .set_default("mqtt_topic_data", "/fota/data/{device_id}/{chunk_id}")?
+            .set_default("mqtt_topic_status", "/fota/status/{instance_id}")?)
+    }
+
+    fn override_from_cli(
+        builder: ConfigBuilder<DefaultState>,
+        key: &str,
+        value: &str,
+    ) -> Result<ConfigBuilder<DefaultState>, SettingsError> {
+        let as_toml = format!("{key} = {value}");
+        let parsed = Config::builder()
+            .add_source(File::from_str(&as_toml, FileFormat::Toml))
+            .build();
+        Ok(match parsed {
+            Ok(_) => builder.add_source(File::from_str(&as_toml, FileFormat::Toml)),
+            Err(_) => builder.set_override(key, value)?,
+        })
+    }
+
+    /// Check what deserialization can't, error always name the offending key
+    fn validate(&self) -> Result<(), SettingsError> {
+        let invalid = |key, reason: &str| {
+            Err(SettingsError::Invalid {
+                key,
+                reason: reason.to_string(),
+            })
+        };
+
+        if self.job_max_running == 0 {
+            return invalid("job_max_running", "must be at least 1");
+        }
+        if self.chunk_size_per_transmission == 0 {
+            return invalid("chunk_size_per_transmission", "must be at least 1");
+        }
+        if self.mqtt_client_id.is_empty() {
+            return invalid("mqtt_client_id", "must not be empty");
+        }
+        if self.mqtt_host.is_empty() {
+            return invalid("mqtt_host", "must not be empty");
+        }
+        if let Some(endpoint) = &self.otel_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return invalid("otel_endpoint", "must be an http or https url");
+            }
+        }
+        if let Some(reason) = self
+            .webhook_urls
+            .iter()
+            .find_map(|url| webhook::validate_url(url).err())
+        {
+            return invalid("webhook_urls", &reason);
+        }
+        if self.webhook_max_attempts == 0 {
+            return invalid("webhook_max_attempts", "must be at least 1");
+        }
+        if self.webhook_retry_max_ms < self.webhook_retry_ms {
+            return invalid(
+                "webhook_retry_max_ms",
+                "must not be less than webhook_retry_ms",
+            );
+        }
+        if self.webhook_queue_size == 0 {
+            return invalid("webhook_queue_size", "must be at least 1");
+        }
+        if self.job_max_send_failures == 0 {
+            return invalid("job_max_send_failures", "must be at least 1");
+        }
+        if self.job_ack_timeout_ms == 0 {
+            return invalid("job_ack_timeout_ms", "must be at least 1");
+        }
+        if self.mqtt_request_capacity == 0 {
+            return invalid("mqtt_request_capacity", "must be at least 1");
+        }
+        if self.mqtt_reconnect_min_ms == 0 {
+            return invalid("mqtt_reconnect_min_ms", "must be at least 1");
+        }
+        if self.mqtt_reconnect_max_ms < self.mqtt_reconnect_min_ms {
+            return invalid(
+                "mqtt_reconnect_max_ms",
+                "must not be less than mqtt_reconnect_min_ms",
+            );
+        }
+        if self.http_tls_cert.is_some() != self.http_tls_key.is_some() {
+            return invalid("http_tls_key", "http_tls_cert and http_tls_key go together");
+        }
+        if self.http_auth_disabled && (!self.api_tokens.is_empty() || self.api_token_file.is_some())
+        {
+            return invalid(
+                "http_auth_disabled",
+                "must not be set along with api_tokens or api_token_file",
+            );
+        }
+        if self.mqtt_tls_client_cert.is_some() != self.mqtt_tls_client_key.is_some() {
+            return invalid(
+                "mqtt_tls_client_key",
+                "mqtt_tls_client_cert and mqtt_tls_client_key go together",
+            );
+        }
+        if let Some(Err(reason)) = self.mqtt_tenant.as_deref().map(topic::validate_level) {
+            return invalid("mqtt_tenant", &reason);
+        }
+        if let Some(group) = &self.mqtt_v5_shared_group {
+            if self.mqtt_protocol != MqttProtocol::V5 {
+                return invalid("mqtt_v5_shared_group", "needs mqtt_protocol v5");
+            }
+            if let Err(reason) = topic::validate_level(group) {
+                return invalid("mqtt_v5_shared_group", &reason);
+            }
+            // Transfer token is only known by the instance that sent the command
+            if !self.mqtt_topic_cmd_resp.contains(topic::INSTANCE_ID) {
+                return invalid(
+                    "mqtt_topic_cmd_resp",
+                    "must contain {instance_id} with mqtt_v5_shared_group",
+                );
+            }
+        }
+        Topics::from_settings(self)?;
+        Ok(())
+    }
+
+    /// Username and password for the mqtt broker, `mqtt_password` wins over `mqtt_password_file`
+    pub fn mqtt_credentials(&self) -> Result<Option<(String, String)>, Box<dyn Error>> {
+        let Some(username) = &self.mqtt_username else {
+            return Ok(None);
+        };
+
+        let password = match (&self.mqtt_password, &self.mqtt_password_file) {
+            (Some(password), _) => password.clone(),
+            (None, Some(path)) => fs::read_to_string(path)
+                .map_err(|err| format!("{path} ({err})"))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+            (None, None) => String::new(),
+        };
+        Ok(Some((username.clone(), password)))
     }
 }
 
+static S: OnceLock<Settings> = OnceLock::new();
+
+/// Load settings once at startup, every later `settings()` call gets the same value
+pub fn init(cli: &Cli) -> Result<&'static Settings, SettingsError> {
+    let settings = Settings::load(cli)?;
+    Ok(S.get_or_init(|| settings))
+}
+
 pub fn settings() -> &'static Settings {
-    static S: OnceLock<Settings> = OnceLock::new();
-    S.get_or_init(|| Settings::new().unwrap())
+    // Only reached without init (e.g. in tests), fall back to the default layers
+    S.get_or_init(|| Settings::load(&Cli::default()).unwrap_or_else(|err| panic!("{err}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(config: Option<&str>, overrides: &[&str]) -> Cli {
+        Cli {
+            config: config.map(PathBuf::from),
+            overrides: overrides
+                .iter()
+                .map(|arg| parse_override(arg).unwrap())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_defaults_only() {
+        let settings: Settings = Settings::defaults(Config::builder())
+            .unwrap()
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert_eq!(settings.mqtt_port, 1883);
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn test_explicit_config_must_exist() {
+        let err = Settings::load(&cli(Some("/nonexistent/rocky.toml"), &[])).unwrap_err();
+        assert!(matches!(err, SettingsError::Load(_)));
+    }
+
+    #[test]
+    fn test_layer_precedence() {
+        // rocky.toml says 16384
+        assert_eq!(
+            Settings::load(&cli(None, &[])).unwrap().http_max_body_bytes,
+            16384
+        );
+
+        let env = Map::from([(
+            String::from("ROCKY_HTTP_MAX_BODY_BYTES"),
+            String::from("2048"),
+        )]);
+        let from_env = Settings::load_with_env(&cli(None, &[]), Some(env.clone())).unwrap();
+        let from_cli =
+            Settings::load_with_env(&cli(None, &["http_max_body_bytes=1024"]), Some(env)).unwrap();
+
+        assert_eq!(from_env.http_max_body_bytes, 2048);
+        assert_eq!(from_cli.http_max_body_bytes, 1024);
+    }
+
+    #[test]
+    fn test_cli_override_values() {
+        let settings = Settings::load(&cli(
+            None,
+            &["mqtt_host=broker.local", r#"mqtt_tls_alpn=["mqtt", "x"]"#],
+        ))
+        .unwrap();
+        assert_eq!(settings.mqtt_host, "broker.local");
+        assert_eq!(settings.mqtt_tls_alpn, ["mqtt", "x"]);
+        assert!(parse_override("no_value").is_err());
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(
+            Settings::load(&cli(None, &[])).unwrap().log_format,
+            LogFormat::Text
+        );
+        let settings = Settings::load(&cli(None, &["log_format=json"])).unwrap();
+        assert_eq!(settings.log_format, LogFormat::Json);
+        assert!(Settings::load(&cli(None, &["log_format=xml"])).is_err());
+    }
+
+    #[test]
+    fn test_otel_endpoint() {
+        let settings = Settings::load(&cli(None, &[])).unwrap();
+        assert_eq!(settings.otel_endpoint, None);
+        assert_eq!(settings.otel_service_name, "rocky");
+
+        let err = Settings::load(&cli(None, &["otel_endpoint=localhost:4318"])).unwrap_err();
+        assert!(err.to_string().contains("otel_endpoint"));
+    }
+
+    #[test]
+    fn test_invalid_topic_names_key() {
+        let err = Settings::load(&cli(
+            None,
+            &["mqtt_topic_cmd_resp=tenants/{tenant}/{device_id}"],
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("mqtt_topic_cmd_resp"));
+    }
+
+    #[test]
+    fn test_qos_settings() {
+        let settings = Settings::load(&cli(None, &["mqtt_qos_data=0"])).unwrap();
+        assert_eq!(settings.mqtt_qos_data, Qos::AtMostOnce);
+        assert_eq!(settings.mqtt_qos_response, Qos::AtLeastOnce);
+
+        let err = Settings::load(&cli(None, &["mqtt_qos_command=3"])).unwrap_err();
+        assert!(err.to_string().contains("qos must be 0, 1 or 2"), "{err}");
+    }
+
+    #[test]
+    fn test_invalid_setting_names_key() {
+        let err = Settings::load(&cli(None, &["chunk_size_per_transmission=0"])).unwrap_err();
+        assert!(err.to_string().contains("chunk_size_per_transmission"));
+
+        let err = Settings::load(&cli(None, &["http_port=70000"])).unwrap_err();
+        assert!(err.to_string().contains("http_port"));
+
+        let err = Settings::load(&cli(None, &["mqtt_tenant=acme/eu"])).unwrap_err();
+        assert!(err.to_string().contains("mqtt_tenant"));
+
+        let err = Settings::load(&cli(None, &["mqtt_v5_shared_group=rocky"])).unwrap_err();
+        assert!(err.to_string().contains("needs mqtt_protocol v5"), "{err}");
+        let err = Settings::load(&cli(
+            None,
+            &["mqtt_protocol=v5", "mqtt_v5_shared_group=rocky/+"],
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("mqtt_v5_shared_group"));
+        let err = Settings::load(&cli(
+            None,
+            &["mqtt_protocol=v5", "mqtt_v5_shared_group=rocky"],
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("mqtt_topic_cmd_resp"), "{err}");
+        assert!(Settings::load(&cli(
+            None,
+            &[
+                "mqtt_protocol=v5",
+                "mqtt_v5_shared_group=rocky",
+                "mqtt_topic_cmd_resp=/fota/cmd_resp/{instance_id}/{device_id}"
+            ]
+        ))
+        .is_ok());
+
+        let err = Settings::load(&cli(
+            None,
+            &["http_auth_disabled=true", "api_token_file=/run/tokens"],
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("http_auth_disabled"));
+
+        let err = Settings::load(&cli(None, &["webhook_retry_max_ms=10"])).unwrap_err();
+        assert!(err.to_string().contains("webhook_retry_max_ms"));
+    }
+
+    fn with_credentials(
+        username: Option<&str>,
+        password: Option<&str>,
+        password_file: Option<&str>,
+    ) -> Settings {
+        let mut settings = Settings::load(&Cli::default()).unwrap();
+        settings.mqtt_username = username.map(String::from);
+        settings.mqtt_password = password.map(String::from);
+        settings.mqtt_password_file = password_file.map(String::from);
+        settings
+    }
+
+    #[test]
+    fn test_mqtt_credentials() {
+        let settings = with_credentials(None, Some("secret"), None);
+        assert_eq!(settings.mqtt_credentials().unwrap(), None);
+
+        let settings = with_credentials(Some("rocky"), Some("secret"), Some("/nonexistent"));
+        assert_eq!(
+            settings.mqtt_credentials().unwrap(),
+            Some((String::from("rocky"), String::from("secret")))
+        );
+    }
+
+    #[test]
+    fn test_mqtt_password_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("mqtt_password");
+        fs::write(&path, "from file\n").unwrap();
+
+        let settings = with_credentials(Some("rocky"), None, path.to_str());
+        assert_eq!(
+            settings.mqtt_credentials().unwrap(),
+            Some((String::from("rocky"), String::from("from file")))
+        );
+
+        let settings = with_credentials(Some("rocky"), None, Some("/nonexistent"));
+        assert!(settings.mqtt_credentials().is_err());
+    }
 }
@@ -1,15 +1,84 @@
-use crate::jobs::JobId;
+use crate::custom_error::TelemetryError;
+use crate::jobs::TransferToken;
+use crate::settings::settings;
+use crate::topic::{topics, TopicTemplate};
 use ciborium::{de, ser};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::io::Cursor;
 
-#[derive(Debug)]
+/// Mqtt quality of service, configured as 0, 1 or 2
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u8")]
+pub enum Qos {
+    AtMostOnce,
+    #[default]
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl TryFrom<u8> for Qos {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::AtMostOnce),
+            1 => Ok(Self::AtLeastOnce),
+            2 => Ok(Self::ExactlyOnce),
+            other => Err(format!("qos must be 0, 1 or 2, got {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct Telemetry {
     pub topic: String,
     pub payload: Vec<u8>,
+    pub qos: Qos,
+    /// Broker keeps the last retained message of a topic for new subscriber
+    pub retain: bool,
+    /// Mqtt v5 only, where the device should publish its response
+    pub response_topic: Option<String>,
+    /// Mqtt v5 only, transfer token as big endian u32 that the device echoes back
+    pub correlation_data: Option<Vec<u8>>,
+    /// Mqtt v5 only, broker drops the message when not delivered in time
+    pub message_expiry_secs: Option<u32>,
 }
 
-#[derive(Debug)]
+/// Device asking whether there is a firmware for it, published on `mqtt_topic_check`
+#[derive(Debug, PartialEq, Eq)]
+pub struct UpdateCheck {
+    pub device_id: String,
+    pub hardware_model: String,
+    pub firmware_version: String,
+}
+
+/// Device online or offline, reported on `mqtt_topic_presence`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Presence {
+    pub device_id: String,
+    pub online: bool,
+}
+
+/// Rocky own status, so devices and monitoring know whether the service is up
+#[derive(Debug, Serialize)]
+struct ServiceStatus<'a> {
+    status: &'a str,
+    version: &'a str,
+    instance_id: &'a str,
+}
+
+/// Command response sent by a device on `mqtt_topic_cmd_resp` topic
+#[derive(Debug, PartialEq, Eq)]
+pub struct CommandResponse {
+    pub token: TransferToken,
+    pub device_id: String,
+    pub command: CommandType,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum CommandType {
     OtaRequest = 0x01,
@@ -18,33 +87,48 @@ pub enum CommandType {
     OtaDone,
     OtaDoneSuccess,
     OtaDoneFailed,
+    OtaUpToDate,
 }
 
-impl From<u8> for CommandType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for CommandType {
+    type Error = TelemetryError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0x01 => Self::OtaRequest,
-            0x02 => Self::OtaRequestAck,
-            0x03 => Self::OtaRequestNack,
-            0x04 => Self::OtaDone,
-            0x05 => Self::OtaDoneSuccess,
-            0x06 => Self::OtaDoneFailed,
-            _ => Self::OtaRequest, // TODO: what is the default?
+            0x01 => Ok(Self::OtaRequest),
+            0x02 => Ok(Self::OtaRequestAck),
+            0x03 => Ok(Self::OtaRequestNack),
+            0x04 => Ok(Self::OtaDone),
+            0x05 => Ok(Self::OtaDoneSuccess),
+            0x06 => Ok(Self::OtaDoneFailed),
+            0x07 => Ok(Self::OtaUpToDate),
+            other => Err(TelemetryError::UnknownCommand(other)),
         }
     }
 }
 
+impl Telemetry {
+    /// Device id of a command response topic, for logging where the telemetry came from
+    pub fn device_id(&self) -> &str {
+        topics()
+            .cmd_resp
+            .device_id(&self.topic)
+            .unwrap_or("unknown")
+    }
+}
+
 pub fn build_command(
-    job_id: JobId,
-    device_id: &String,
+    token: TransferToken,
+    device_id: &str,
     cmd: CommandType,
-    image_hash: &Vec<u8>,
+    image_hash: &[u8],
+    qos: Qos,
 ) -> Result<Telemetry, Box<dyn Error>> {
     // Format topic
-    let topic: String = format!("/fota/cmd/{device_id}");
+    let topic: String = topics().cmd.render(device_id);
 
     // Encode payload to cbor
-    let payload = (job_id, cmd as u8, image_hash);
+    let payload = (token, cmd as u8, image_hash);
     let mut buff = Vec::new();
     ser::into_writer(&payload, &mut buff)?;
 
@@ -52,6 +136,10 @@ pub fn build_command(
     let payload = Telemetry {
         topic,
         payload: buff,
+        qos,
+        response_topic: Some(topics().cmd_resp.render(device_id)),
+        correlation_data: Some(token.to_be_bytes().to_vec()),
+        ..Default::default()
     };
     debug!("Payload: {payload:?}");
 
@@ -59,27 +147,297 @@ pub fn build_command(
 }
 
 /// No cbor encoding happen for chunks data, because it already in bytes
-pub fn build_packet(device_id: &String, chunk_id: u16, chunk: bytes::Bytes) -> Telemetry {
+pub fn build_packet(device_id: &str, chunk_id: u16, chunk: bytes::Bytes, qos: Qos) -> Telemetry {
     // Format topic
-    let topic: String = format!("/fota/data/{device_id}/{chunk_id}");
+    let topic: String = topics().data.render_chunk(device_id, chunk_id);
 
     // Build telemetry data
     let payload = Telemetry {
         topic,
         payload: chunk.to_vec(),
+        qos,
+        message_expiry_secs: settings().mqtt_v5_chunk_expiry_secs,
+        ..Default::default()
     };
     trace!("{payload:?}");
 
     payload
 }
 
-pub fn parse(tlm: Telemetry) -> Result<(JobId, CommandType), Box<dyn Error>> {
-    // let topic_path: Vec<&str> = tlm.topic.split("/").collect();
-    // TODO: Define type later either command or chunk. If not for command directly return
+/// Retained json status on `mqtt_topic_status`, offline one is registered as last will
+pub fn build_status(online: bool) -> Telemetry {
+    let status = ServiceStatus {
+        status: if online { "online" } else { "offline" },
+        version: env!("CARGO_PKG_VERSION"),
+        instance_id: &settings().mqtt_client_id,
+    };
+    Telemetry {
+        topic: topics().status.clone(),
+        payload: serde_json::to_vec(&status).unwrap_or_default(),
+        qos: Qos::AtLeastOnce,
+        retain: true,
+        ..Default::default()
+    }
+}
+
+pub fn parse(tlm: &Telemetry) -> Result<CommandResponse, TelemetryError> {
+    // Only command response topic is expected here, the device id is the only variable part
+    let Some(device_id) = topics().cmd_resp.device_id(&tlm.topic) else {
+        return Err(TelemetryError::UnexpectedTopic(tlm.topic.clone()));
+    };
+
+    // (TransferToken, CommandType)
+    let deserialized: (TransferToken, u8) = de::from_reader(&mut Cursor::new(&tlm.payload))
+        .map_err(|err| TelemetryError::Malformed(err.to_string()))?;
 
-    // (jobId, CommandType)
-    let deserialized: (JobId, u8) = de::from_reader(&mut Cursor::new(tlm.payload))?;
-    let parsed = (deserialized.0, CommandType::from(deserialized.1));
+    // Mqtt v5 device echoes correlation data, it has to agree with the token in the payload
+    if let Some(correlation) = &tlm.correlation_data {
+        if correlation[..] != deserialized.0.to_be_bytes() {
+            return Err(TelemetryError::Malformed(String::from(
+                "correlation data does not match transfer token",
+            )));
+        }
+    }
+    let parsed = CommandResponse {
+        token: deserialized.0,
+        device_id: device_id.to_string(),
+        command: CommandType::try_from(deserialized.1)?,
+    };
     debug!("Parsed notification: {:?}", parsed);
     Ok(parsed)
 }
+
+pub fn parse_update_check(tlm: &Telemetry) -> Result<UpdateCheck, TelemetryError> {
+    parse_update_check_on(topics().check.as_ref(), tlm)
+}
+
+/// Update check on the given check topic, none when pull mode is off
+fn parse_update_check_on(
+    check: Option<&TopicTemplate>,
+    tlm: &Telemetry,
+) -> Result<UpdateCheck, TelemetryError> {
+    let Some(device_id) = check.and_then(|check| check.device_id(&tlm.topic)) else {
+        return Err(TelemetryError::UnexpectedTopic(tlm.topic.clone()));
+    };
+
+    // (hardware_model, firmware_version)
+    let (hardware_model, firmware_version): (String, String) =
+        de::from_reader(&mut Cursor::new(&tlm.payload))
+            .map_err(|err| TelemetryError::Malformed(err.to_string()))?;
+    Ok(UpdateCheck {
+        device_id: device_id.to_string(),
+        hardware_model,
+        firmware_version,
+    })
+}
+
+/// Presence message is either plain `online`/`offline` (e.g. device last will),
+/// or json with `status` like rocky own status or `connected` bool like broker client events
+pub fn parse_presence(tlm: &Telemetry) -> Result<Presence, TelemetryError> {
+    let Some(device_id) = topics()
+        .presence
+        .as_ref()
+        .and_then(|presence| presence.device_id(&tlm.topic))
+    else {
+        return Err(TelemetryError::UnexpectedTopic(tlm.topic.clone()));
+    };
+
+    let Some(online) = presence_payload(&tlm.payload) else {
+        return Err(TelemetryError::Malformed(String::from(
+            "presence is neither online nor offline",
+        )));
+    };
+    Ok(Presence {
+        device_id: device_id.to_string(),
+        online,
+    })
+}
+
+fn presence_payload(payload: &[u8]) -> Option<bool> {
+    let text = std::str::from_utf8(payload).ok()?.trim();
+    let from_text = |text: &str| match text.to_ascii_lowercase().as_str() {
+        "online" | "connected" | "true" | "1" => Some(true),
+        "offline" | "disconnected" | "false" | "0" => Some(false),
+        _ => None,
+    };
+    if let Some(online) = from_text(text) {
+        return Some(online);
+    }
+
+    let json: serde_json::Value = serde_json::from_str(text).ok()?;
+    match (json.get("connected"), json.get("status")) {
+        (Some(serde_json::Value::Bool(connected)), _) => Some(*connected),
+        (_, Some(serde_json::Value::String(status))) => from_text(status),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn response(payload: Vec<u8>) -> Telemetry {
+        Telemetry {
+            topic: String::from("/fota/cmd_resp/device1"),
+            payload,
+            ..Default::default()
+        }
+    }
+
+    fn encode<T: serde::Serialize>(value: &T) -> Vec<u8> {
+        let mut buff = Vec::new();
+        ser::into_writer(value, &mut buff).unwrap();
+        buff
+    }
+
+    #[test]
+    fn test_parse_valid_response() {
+        let tlm = response(encode(&(123456u32, CommandType::OtaRequestAck as u8)));
+        assert_eq!(
+            parse(&tlm),
+            Ok(CommandResponse {
+                token: 123456,
+                device_id: String::from("device1"),
+                command: CommandType::OtaRequestAck
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_unexpected_topic() {
+        let payload = encode(&(123456u32, CommandType::OtaRequestAck as u8));
+        for topic in [
+            "/fota/cmd_resp/",
+            "/fota/cmd_resp/a/b",
+            "/fota/cmd/device1",
+            "device1",
+        ] {
+            let tlm = Telemetry {
+                topic: String::from(topic),
+                payload: payload.clone(),
+                ..Default::default()
+            };
+            assert_eq!(
+                parse(&tlm),
+                Err(TelemetryError::UnexpectedTopic(String::from(topic)))
+            );
+        }
+    }
+
+    #[test]
+    fn test_qos_from_u8() {
+        assert_eq!(Qos::try_from(0), Ok(Qos::AtMostOnce));
+        assert_eq!(Qos::try_from(2), Ok(Qos::ExactlyOnce));
+        assert!(Qos::try_from(3).is_err());
+    }
+
+    #[test]
+    fn test_parse_correlation_data() {
+        let mut tlm = response(encode(&(123456u32, CommandType::OtaRequestAck as u8)));
+        tlm.correlation_data = Some(123456u32.to_be_bytes().to_vec());
+        assert!(parse(&tlm).is_ok());
+
+        tlm.correlation_data = Some(654321u32.to_be_bytes().to_vec());
+        assert!(matches!(parse(&tlm), Err(TelemetryError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_parse_unknown_command() {
+        let tlm = response(encode(&(123456u32, 0x7fu8)));
+        assert_eq!(parse(&tlm), Err(TelemetryError::UnknownCommand(0x7f)));
+    }
+
+    #[test]
+    fn test_parse_malformed_payload() {
+        let tlm = response(b"not cbor at all".to_vec());
+        assert!(matches!(parse(&tlm), Err(TelemetryError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_parse_update_check() {
+        // Check topic isn't set in the test settings
+        let tlm = Telemetry {
+            topic: String::from("/fota/check/device1"),
+            payload: encode(&("esp32", "1.0.0")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse_update_check(&tlm),
+            Err(TelemetryError::UnexpectedTopic(_))
+        ));
+
+        let check = TopicTemplate::new_matchable("/fota/check/{device_id}", None).unwrap();
+        let parsed = parse_update_check_on(Some(&check), &tlm).unwrap();
+        assert_eq!(parsed.device_id, "device1");
+        assert_eq!(parsed.hardware_model, "esp32");
+        assert_eq!(parsed.firmware_version, "1.0.0");
+
+        let malformed = Telemetry {
+            payload: encode(&("esp32",)),
+            ..tlm
+        };
+        assert!(matches!(
+            parse_update_check_on(Some(&check), &malformed),
+            Err(TelemetryError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_presence_payload() {
+        assert_eq!(presence_payload(b"online"), Some(true));
+        assert_eq!(presence_payload(b"OFFLINE\n"), Some(false));
+        assert_eq!(presence_payload(br#"{"status": "online"}"#), Some(true));
+        assert_eq!(
+            presence_payload(br#"{"clientid": "musang", "connected": false}"#),
+            Some(false)
+        );
+        assert_eq!(presence_payload(b"maybe"), None);
+        assert_eq!(presence_payload(&[0xff, 0xfe]), None);
+    }
+
+    #[test]
+    fn test_build_status() {
+        let online = build_status(true);
+        assert_eq!(online.topic, "/fota/status/rocky");
+        assert!(online.retain);
+
+        let payload: serde_json::Value = serde_json::from_slice(&online.payload).unwrap();
+        assert_eq!(payload["status"], "online");
+        assert_eq!(payload["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(payload["instance_id"], "rocky");
+
+        let offline: serde_json::Value =
+            serde_json::from_slice(&build_status(false).payload).unwrap();
+        assert_eq!(offline["status"], "offline");
+    }
+
+    #[test]
+    fn test_device_id_from_topic() {
+        assert_eq!(response(Vec::new()).device_id(), "device1");
+        let other = Telemetry {
+            topic: String::from("/fota/cmd/device1"),
+            ..Default::default()
+        };
+        assert_eq!(other.device_id(), "unknown");
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics_on_arbitrary_bytes(payload in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let _ = parse(&response(payload));
+        }
+
+        #[test]
+        fn parse_accepts_only_known_commands(token in any::<TransferToken>(), cmd in any::<u8>()) {
+            let parsed = parse(&response(encode(&(token, cmd))));
+            match CommandType::try_from(cmd) {
+                Ok(command) => prop_assert_eq!(
+                    parsed,
+                    Ok(CommandResponse { token, device_id: String::from("device1"), command })
+                ),
+                Err(_) => prop_assert_eq!(parsed, Err(TelemetryError::UnknownCommand(cmd))),
+            }
+        }
+    }
+}
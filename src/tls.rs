@@ -0,0 +1,342 @@
+use axum_server::tls_rustls::RustlsConfig;
+use rumqttc::{TlsConfiguration, Transport};
+use rustls::crypto::ring;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::error::Error;
+use std::fs;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Certificate files of the https server, read again from disk on every reload
+#[derive(Debug, Clone)]
+pub struct HttpTls {
+    pub cert: String,
+    pub key: String,
+    /// When set, clients must present a certificate signed by this CA
+    pub client_ca: Option<String>,
+}
+
+impl HttpTls {
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+        let provider = Arc::new(ring::default_provider());
+
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .map_err(|err| format!("{} ({err})", self.cert))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .map_err(|err| format!("{} ({err})", self.key))?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(client_ca)
+                    .map_err(|err| format!("{client_ca} ({err})"))?
+                {
+                    roots.add(cert?)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    /// Swap certificate on SIGHUP, only new connection will use it so nothing in flight is dropped.
+    /// Failed reload keeps the previous certificate.
+    pub async fn reload_on_sighup(self, config: RustlsConfig) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                error!("Can't listen to SIGHUP, certificate reload disabled ({err})");
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading http certificate");
+            match self.server_config() {
+                Ok(server_config) => {
+                    config.reload_from_config(server_config);
+                    info!("Http certificate reloaded");
+                }
+                Err(err) => error!("Reload http certificate failed, keep the old one ({err})"),
+            }
+        }
+    }
+}
+
+/// Tls transport of the mqtt connection
+#[derive(Debug, Clone, Default)]
+pub struct MqttTls {
+    /// Broker CA, platform certificates are used when not set
+    pub ca: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub alpn: Vec<String>,
+}
+
+impl MqttTls {
+    pub fn transport(&self) -> Result<Transport, Box<dyn Error>> {
+        let client_auth = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((
+                fs::read(cert).map_err(|err| format!("{cert} ({err})"))?,
+                fs::read(key).map_err(|err| format!("{key} ({err})"))?,
+            )),
+            (None, None) => None,
+            _ => Err("Both mqtt_tls_client_cert and mqtt_tls_client_key must be set")?,
+        };
+        let alpn = (!self.alpn.is_empty())
+            .then(|| self.alpn.iter().map(|p| p.as_bytes().to_vec()).collect());
+
+        let config = match &self.ca {
+            Some(ca) => TlsConfiguration::Simple {
+                ca: fs::read(ca).map_err(|err| format!("{ca} ({err})"))?,
+                alpn,
+                client_auth,
+            },
+            None if client_auth.is_none() && alpn.is_none() => TlsConfiguration::default(),
+            None => Err("mqtt_tls_ca is required to use client certificate or alpn")?,
+        };
+        Ok(Transport::tls_with_config(config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rumqttc::{Client, Event, Incoming, MqttOptions};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::process::Command;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    fn write_file(dir: &Path, name: &str, content: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Self signed certificate for localhost, written to `<name>.crt` and `<name>.key` in the dir
+    fn self_signed(dir: &Path, name: &str) -> HttpTls {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        HttpTls {
+            cert: write_file(dir, &format!("{name}.crt"), &cert.cert.pem()),
+            key: write_file(dir, &format!("{name}.key"), &cert.key_pair.serialize_pem()),
+            client_ca: None,
+        }
+    }
+
+    #[test]
+    fn test_server_config() {
+        let dir = TempDir::new().unwrap();
+        let tls = self_signed(dir.path(), "server");
+        assert!(tls.server_config().is_ok());
+    }
+
+    #[test]
+    fn test_server_config_with_client_ca() {
+        let dir = TempDir::new().unwrap();
+        let mut tls = self_signed(dir.path(), "mtls");
+        tls.client_ca = Some(tls.cert.clone());
+        assert!(tls.server_config().is_ok());
+    }
+
+    #[test]
+    fn test_server_config_missing_file() {
+        let dir = TempDir::new().unwrap();
+        let mut tls = self_signed(dir.path(), "missing");
+        tls.key = String::from("/nonexistent/rocky.key");
+        let err = tls.server_config().unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/rocky.key"));
+    }
+
+    /// CA plus a server and a client certificate signed by it, written as pem files
+    struct Pki {
+        ca: String,
+        server: HttpTls,
+        client_cert: String,
+        client_key: String,
+    }
+
+    fn pki(dir: &Path) -> Pki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let sign = |names: Vec<String>| {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(names)
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+            (cert.pem(), key.serialize_pem())
+        };
+        let (server_cert, server_key) = sign(vec![String::from("localhost")]);
+        let (client_cert, client_key) = sign(vec![String::from("rocky")]);
+
+        let ca = write_file(dir, "ca.crt", &ca.pem());
+        Pki {
+            server: HttpTls {
+                cert: write_file(dir, "server.crt", &server_cert),
+                key: write_file(dir, "server.key", &server_key),
+                client_ca: Some(ca.clone()),
+            },
+            ca,
+            client_cert: write_file(dir, "client.crt", &client_cert),
+            client_key: write_file(dir, "client.key", &client_key),
+        }
+    }
+
+    /// Broker stand-in, accept one tls connection and answer CONNECT with CONNACK.
+    /// Return the port and the negotiated alpn once the client connected.
+    fn tls_broker(server: &HttpTls, alpn: &[&str]) -> (u16, thread::JoinHandle<Option<Vec<u8>>>) {
+        let mut config = (*server.server_config().unwrap()).clone();
+        config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let conn = rustls::ServerConnection::new(config).unwrap();
+            let mut stream = rustls::StreamOwned::new(conn, socket);
+
+            // Fixed header of CONNECT, then the rest of the packet
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header).ok()?;
+            assert_eq!(header[0], 0x10);
+            let mut rest = vec![0u8; header[1] as usize];
+            stream.read_exact(&mut rest).ok()?;
+
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).ok()?;
+            stream.flush().ok()?;
+            let negotiated = stream.conn.alpn_protocol().map(|p| p.to_vec());
+            thread::sleep(Duration::from_millis(100));
+            Some(negotiated.unwrap_or_default())
+        });
+        (port, handle)
+    }
+
+    /// Connect to the broker stand-in and report whether CONNACK arrived
+    fn connect(port: u16, tls: &MqttTls) -> bool {
+        let mut options = MqttOptions::new("rocky-test", "localhost", port);
+        options.set_transport(tls.transport().unwrap());
+        let (_client, mut connection) = Client::new(options, 10);
+        for event in connection.iter().take(5) {
+            match event {
+                Ok(Event::Incoming(Incoming::ConnAck(_))) => return true,
+                Ok(_) => continue,
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn test_mqtt_tls_with_client_certificate() {
+        let dir = TempDir::new().unwrap();
+        let pki = pki(dir.path());
+        let (port, broker) = tls_broker(&pki.server, &["mqtt"]);
+        let tls = MqttTls {
+            ca: Some(pki.ca),
+            client_cert: Some(pki.client_cert),
+            client_key: Some(pki.client_key),
+            alpn: vec![String::from("mqtt")],
+        };
+
+        assert!(connect(port, &tls));
+        assert_eq!(broker.join().unwrap(), Some(b"mqtt".to_vec()));
+    }
+
+    #[test]
+    fn test_mqtt_tls_rejected_without_client_certificate() {
+        let dir = TempDir::new().unwrap();
+        let pki = pki(dir.path());
+        let (port, broker) = tls_broker(&pki.server, &[]);
+        let tls = MqttTls {
+            ca: Some(pki.ca),
+            ..Default::default()
+        };
+
+        assert!(!connect(port, &tls));
+        assert_eq!(broker.join().unwrap(), None);
+    }
+
+    #[test]
+    fn test_mqtt_tls_incomplete_client_auth() {
+        let tls = MqttTls {
+            client_cert: Some(String::from("/etc/rocky/client.crt")),
+            ..Default::default()
+        };
+        assert!(tls.transport().is_err());
+    }
+
+    #[test]
+    fn test_reload_keeps_config_swappable() {
+        let dir = TempDir::new().unwrap();
+        let first = self_signed(dir.path(), "first");
+        let second = self_signed(dir.path(), "second");
+        let config = RustlsConfig::from_config(first.server_config().unwrap());
+        let before = config.get_inner();
+
+        config.reload_from_config(second.server_config().unwrap());
+        assert!(!Arc::ptr_eq(&before, &config.get_inner()));
+    }
+
+    fn send_sighup() {
+        let status = Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn test_reload_on_sighup() {
+        // Handler is registered for the whole process before anything sends SIGHUP,
+        // otherwise the default action would kill the test binary
+        let _hangup = signal(SignalKind::hangup()).unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let tls = self_signed(dir.path(), "server");
+        let config = RustlsConfig::from_config(tls.server_config().unwrap());
+        let before = config.get_inner();
+        tokio::spawn(tls.clone().reload_on_sighup(config.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Invalid files keep the previous certificate
+        fs::write(&tls.key, "not a key").unwrap();
+        send_sighup();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(Arc::ptr_eq(&before, &config.get_inner()));
+
+        // Certificate rotated on disk is picked up
+        let rotated = self_signed(dir.path(), "server");
+        assert_eq!(rotated.key, tls.key);
+        send_sighup();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Arc::ptr_eq(&before, &config.get_inner()) {
+            assert!(Instant::now() < deadline, "certificate not reloaded");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::sync::OnceLock;
+
+use crate::custom_error::SettingsError;
+use crate::settings::{settings, Settings};
+
+const DEVICE_ID: &str = "{device_id}";
+const CHUNK_ID: &str = "{chunk_id}";
+const TENANT: &str = "{tenant}";
+pub const INSTANCE_ID: &str = "{instance_id}";
+
+/// Mqtt topic with `{device_id}` and `{chunk_id}` placeholders, `{tenant}` is already filled in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicTemplate {
+    template: String,
+}
+
+impl TopicTemplate {
+    pub fn new(template: &str, tenant: Option<&str>) -> Result<Self, String> {
+        if template.contains(TENANT) {
+            let Some(tenant) = tenant else {
+                return Err(String::from("uses {tenant} but mqtt_tenant is not set"));
+            };
+            return Self::new(&template.replace(TENANT, tenant), None);
+        }
+        if !template.contains(DEVICE_ID) {
+            return Err(String::from("must contain {device_id}"));
+        }
+        if template.contains(['+', '#']) {
+            return Err(String::from("must not contain wildcard"));
+        }
+        Ok(Self {
+            template: template.to_string(),
+        })
+    }
+
+    /// Template that also has to be subscribed or matched, placeholder must take a whole topic level
+    pub fn new_matchable(template: &str, tenant: Option<&str>) -> Result<Self, String> {
+        let topic = Self::new(template, tenant)?;
+        let device_levels = topic.levels().filter(|level| *level == DEVICE_ID).count();
+        if device_levels != 1 || topic.template.matches(DEVICE_ID).count() != 1 {
+            return Err(String::from(
+                "{device_id} must be exactly one whole topic level",
+            ));
+        }
+        if topic.template.contains(CHUNK_ID) {
+            return Err(String::from("must not contain {chunk_id}"));
+        }
+        Ok(topic)
+    }
+
+    pub fn render(&self, device_id: &str) -> String {
+        self.template.replace(DEVICE_ID, device_id)
+    }
+
+    pub fn render_chunk(&self, device_id: &str, chunk_id: u16) -> String {
+        self.render(device_id)
+            .replace(CHUNK_ID, &chunk_id.to_string())
+    }
+
+    /// Subscription filter matching every device
+    pub fn subscription(&self) -> String {
+        self.template.replace(DEVICE_ID, "+")
+    }
+
+    /// Device id of a topic matching this template
+    pub fn device_id<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        let mut device_id = None;
+        let mut levels = topic.split('/');
+        for expected in self.levels() {
+            let level = levels.next()?;
+            if expected == DEVICE_ID {
+                device_id = Some(level).filter(|level| !level.is_empty());
+            } else if expected != level {
+                return None;
+            }
+        }
+        if levels.next().is_some() {
+            return None;
+        }
+        device_id
+    }
+
+    fn levels(&self) -> impl Iterator<Item = &str> {
+        self.template.split('/')
+    }
+}
+
+/// Value filled into a placeholder must stay one topic level and must not be a wildcard
+pub fn validate_level(value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        return Err(String::from("must not be empty"));
+    }
+    if value.contains(['/', '+', '#']) {
+        return Err(String::from("must not contain '/', '+' or '#'"));
+    }
+    Ok(())
+}
+
+/// Topic that isn't about a device, e.g. rocky own status. `{tenant}` and `{instance_id}` are filled in
+pub fn service_topic(
+    template: &str,
+    tenant: Option<&str>,
+    instance_id: &str,
+) -> Result<String, String> {
+    if template.contains(TENANT) && tenant.is_none() {
+        return Err(String::from("uses {tenant} but mqtt_tenant is not set"));
+    }
+    if template.contains(['+', '#']) {
+        return Err(String::from("must not contain wildcard"));
+    }
+    Ok(fill_instance_id(template, instance_id)?.replace(TENANT, tenant.unwrap_or_default()))
+}
+
+/// Fill in `{instance_id}`, it must stay one topic level
+fn fill_instance_id(template: &str, instance_id: &str) -> Result<String, String> {
+    if !template.contains(INSTANCE_ID) {
+        return Ok(template.to_string());
+    }
+    validate_level(instance_id)
+        .map_err(|reason| format!("{{instance_id}} from mqtt_client_id {reason}"))?;
+    Ok(template.replace(INSTANCE_ID, instance_id))
+}
+
+/// Every topic rocky use, built from the `mqtt_topic_*` settings
+#[derive(Debug, Clone)]
+pub struct Topics {
+    pub cmd: TopicTemplate,
+    /// `{instance_id}` is already filled in, so instances can each get their own responses
+    pub cmd_resp: TopicTemplate,
+    pub data: TopicTemplate,
+    /// Retained online/offline status of this rocky instance
+    pub status: String,
+    /// Where devices report being online or offline, presence isn't tracked when not set
+    pub presence: Option<TopicTemplate>,
+    /// Where devices ask for a firmware update, pull mode is off when not set
+    pub check: Option<TopicTemplate>,
+}
+
+impl Topics {
+    pub fn from_settings(settings: &Settings) -> Result<Self, SettingsError> {
+        let tenant = settings.mqtt_tenant.as_deref();
+        let invalid = |key| move |reason| SettingsError::Invalid { key, reason };
+        Ok(Self {
+            cmd: TopicTemplate::new(&settings.mqtt_topic_cmd, tenant)
+                .map_err(invalid("mqtt_topic_cmd"))?,
+            cmd_resp: fill_instance_id(&settings.mqtt_topic_cmd_resp, &settings.mqtt_client_id)
+                .and_then(|template| TopicTemplate::new_matchable(&template, tenant))
+                .map_err(invalid("mqtt_topic_cmd_resp"))?,
+            data: TopicTemplate::new(&settings.mqtt_topic_data, tenant)
+                .map_err(invalid("mqtt_topic_data"))?,
+            status: service_topic(
+                &settings.mqtt_topic_status,
+                tenant,
+                &settings.mqtt_client_id,
+            )
+            .map_err(invalid("mqtt_topic_status"))?,
+            presence: settings
+                .mqtt_topic_presence
+                .as_deref()
+                .map(|template| TopicTemplate::new_matchable(template, tenant))
+                .transpose()
+                .map_err(invalid("mqtt_topic_presence"))?,
+            check: settings
+                .mqtt_topic_check
+                .as_deref()
+                .map(|template| TopicTemplate::new_matchable(template, tenant))
+                .transpose()
+                .map_err(invalid("mqtt_topic_check"))?,
+        })
+    }
+}
+
+pub fn topics() -> &'static Topics {
+    static T: OnceLock<Topics> = OnceLock::new();
+    // Settings validation already built these once, so it can't fail here
+    T.get_or_init(|| Topics::from_settings(settings()).unwrap_or_else(|err| panic!("{err}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_topics() {
+        let resp = TopicTemplate::new_matchable("/fota/cmd_resp/{device_id}", None).unwrap();
+        assert_eq!(resp.subscription(), "/fota/cmd_resp/+");
+        assert_eq!(resp.device_id("/fota/cmd_resp/musang"), Some("musang"));
+        assert_eq!(resp.device_id("/fota/cmd_resp/"), None);
+        assert_eq!(resp.device_id("/fota/cmd_resp/a/b"), None);
+        assert_eq!(resp.device_id("/fota/cmd/musang"), None);
+
+        let data = TopicTemplate::new("/fota/data/{device_id}/{chunk_id}", None).unwrap();
+        assert_eq!(data.render_chunk("musang", 7), "/fota/data/musang/7");
+    }
+
+    #[test]
+    fn test_tenant_topics() {
+        let template = "tenants/{tenant}/devices/{device_id}/ota/resp";
+        let resp = TopicTemplate::new_matchable(template, Some("acme")).unwrap();
+        assert_eq!(
+            resp.render("musang"),
+            "tenants/acme/devices/musang/ota/resp"
+        );
+        assert_eq!(resp.subscription(), "tenants/acme/devices/+/ota/resp");
+        assert_eq!(
+            resp.device_id("tenants/acme/devices/musang/ota/resp"),
+            Some("musang")
+        );
+        assert_eq!(
+            resp.device_id("tenants/other/devices/musang/ota/resp"),
+            None
+        );
+
+        assert!(TopicTemplate::new(template, None).is_err());
+    }
+
+    #[test]
+    fn test_aws_style_topics() {
+        let resp = TopicTemplate::new_matchable("$aws/things/{device_id}/ota/resp", None).unwrap();
+        assert_eq!(resp.subscription(), "$aws/things/+/ota/resp");
+        assert_eq!(
+            resp.device_id("$aws/things/musang/ota/resp"),
+            Some("musang")
+        );
+    }
+
+    #[test]
+    fn test_service_topic() {
+        assert_eq!(
+            service_topic("/fota/status/{instance_id}", None, "rocky-1"),
+            Ok(String::from("/fota/status/rocky-1"))
+        );
+        assert_eq!(
+            service_topic("tenants/{tenant}/rocky", Some("acme"), "rocky-1"),
+            Ok(String::from("tenants/acme/rocky"))
+        );
+        assert!(service_topic("tenants/{tenant}/rocky", None, "rocky-1").is_err());
+        assert!(service_topic("/fota/status/#", None, "rocky-1").is_err());
+        assert!(service_topic("/fota/status/{instance_id}", None, "rocky/1").is_err());
+    }
+
+    #[test]
+    fn test_validate_level() {
+        assert!(validate_level("musang-01").is_ok());
+        assert!(validate_level("").is_err());
+        assert!(validate_level("a/b").is_err());
+        assert!(validate_level("+").is_err());
+        assert!(validate_level("a#").is_err());
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!(TopicTemplate::new("/fota/cmd", None).is_err());
+        assert!(TopicTemplate::new("/fota/+/{device_id}", None).is_err());
+        assert!(TopicTemplate::new_matchable("/fota/dev-{device_id}", None).is_err());
+        assert!(TopicTemplate::new_matchable("/fota/{device_id}/{chunk_id}", None).is_err());
+        assert!(TopicTemplate::new_matchable("/{device_id}/{device_id}", None).is_err());
+    }
+}
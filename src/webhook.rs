@@ -0,0 +1,531 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TrySendError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+use crate::job_board::{now_ms, JobRecord};
+use crate::jobs::{JobId, JobStatus};
+use crate::settings::settings;
+
+/// Header carrying `sha256=<hex hmac of the body>` when `webhook_secret` is set
+pub const SIGNATURE_HEADER: &str = "X-Rocky-Signature";
+/// Deliveries kept in the log, oldest is dropped first
+const LOG_CAPACITY: usize = 1000;
+/// Worker of a url without any event for this long is stopped once it has nothing left to post
+const WORKER_IDLE: Duration = Duration::from_secs(600);
+
+/// Body posted to every webhook when a job changes status
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct JobEvent {
+    #[schema(value_type = Uuid)]
+    pub job_id: JobId,
+    pub device_id: String,
+    pub status: JobStatus,
+    pub failure_reason: Option<String>,
+    pub image_hash: Option<String>,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+}
+
+/// One event posted to one url, updated on every attempt
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct WebhookDelivery {
+    pub delivery_id: u64,
+    #[schema(value_type = Uuid)]
+    pub job_id: JobId,
+    pub status: JobStatus,
+    pub url: String,
+    pub attempts: u32,
+    pub delivered: bool,
+    /// Http status of the last attempt, unset when the request itself failed
+    pub response_code: Option<u16>,
+    pub error: Option<String>,
+    /// Unix timestamp in milliseconds
+    pub last_attempt_at: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct DeliveryLog {
+    next_id: u64,
+    entries: VecDeque<WebhookDelivery>,
+}
+
+impl DeliveryLog {
+    fn start(&mut self, event: &JobEvent, url: &str) -> u64 {
+        self.next_id += 1;
+        if self.entries.len() >= LOG_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(WebhookDelivery {
+            delivery_id: self.next_id,
+            job_id: event.job_id,
+            status: event.status,
+            url: url.to_string(),
+            attempts: 0,
+            delivered: false,
+            response_code: None,
+            error: None,
+            last_attempt_at: None,
+        });
+        self.next_id
+    }
+
+    fn update(&mut self, delivery_id: u64, f: impl FnOnce(&mut WebhookDelivery)) {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.delivery_id == delivery_id)
+        {
+            f(entry);
+        }
+    }
+}
+
+/// Job status change notifier, events of each url are posted in order from a thread of that url.
+/// Default one has no thread and drops every event.
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+    tx: Option<mpsc::SyncSender<(JobEvent, Vec<String>)>>,
+    log: Arc<RwLock<DeliveryLog>>,
+}
+
+impl Webhooks {
+    /// Spawn the dispatch thread, url threads are spawned on their first event
+    pub fn start() -> Self {
+        let (tx, rx) = mpsc::sync_channel(settings().webhook_queue_size);
+        let webhooks = Self {
+            tx: Some(tx),
+            ..Default::default()
+        };
+        let log = webhooks.log.clone();
+        thread::spawn(move || dispatch(rx, log));
+        webhooks
+    }
+
+    /// Queue the event to the global webhooks and the job `callback_url`,
+    /// only starting, in progress, success and failed are notified
+    pub fn notify(&self, record: &JobRecord) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if !matches!(
+            record.status,
+            JobStatus::Starting | JobStatus::InProgress | JobStatus::Success | JobStatus::Failed
+        ) {
+            return;
+        }
+
+        let urls: Vec<String> = settings()
+            .webhook_urls
+            .iter()
+            .chain(&record.callback_url)
+            .cloned()
+            .collect();
+        if urls.is_empty() {
+            return;
+        }
+        let event = JobEvent {
+            job_id: record.job_id,
+            device_id: record.device_id.clone(),
+            status: record.status,
+            failure_reason: record.failure_reason.clone(),
+            image_hash: record.image_hash.clone(),
+            timestamp: now_ms(),
+        };
+        match tx.try_send((event, urls)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => error!(
+                "Webhook queue is full, event of job {} dropped",
+                record.job_id
+            ),
+            Err(TrySendError::Disconnected(_)) => error!(
+                "Webhook thread is not running, event of job {} dropped",
+                record.job_id
+            ),
+        }
+    }
+
+    /// Deliveries oldest first, only the ones of `job_id` when it is given
+    pub fn deliveries(&self, job_id: Option<JobId>) -> Vec<WebhookDelivery> {
+        let log = self.log.read().unwrap();
+        log.entries
+            .iter()
+            .filter(|entry| job_id.is_none_or(|job_id| entry.job_id == job_id))
+            .cloned()
+            .collect()
+    }
+}
+
+pub fn validate_url(url: &str) -> Result<(), String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(())
+    } else {
+        Err(format!("{url} must be an http or https url"))
+    }
+}
+
+/// Job creator picks the callback url, so it may only point to one of `hosts`,
+/// not to whatever else rocky can reach
+pub fn validate_callback_url(url: &str, hosts: &[String]) -> Result<(), String> {
+    validate_url(url)?;
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from));
+    match host {
+        Some(host)
+            if hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&host)) =>
+        {
+            Ok(())
+        }
+        _ => Err(format!("{url} host is not in webhook_callback_hosts")),
+    }
+}
+
+/// `sha256=<hex>` of the body keyed with the secret
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts key of any length");
+    mac.update(body);
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("sha256={signature}")
+}
+
+/// Event with its body, queued to the thread of one url
+struct Delivery {
+    event: JobEvent,
+    body: Arc<Vec<u8>>,
+}
+
+/// Thread posting the events of one url one after another
+struct Worker {
+    tx: mpsc::SyncSender<Delivery>,
+    last_used: Instant,
+    /// Deliveries queued or being posted, including their retries
+    pending: Arc<AtomicUsize>,
+}
+
+impl Worker {
+    fn spawn(url: &str, client: reqwest::blocking::Client, log: Arc<RwLock<DeliveryLog>>) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Delivery>(settings().webhook_queue_size);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let url = url.to_string();
+        thread::spawn({
+            let pending = pending.clone();
+            move || {
+                debug!("Run webhook thread of {url}");
+                // Ends once the dispatcher drops the sender and the queue is drained
+                for delivery in rx {
+                    let delivery_id = log.write().unwrap().start(&delivery.event, &url);
+                    deliver(&client, &url, &delivery.body, &log, delivery_id);
+                    pending.fetch_sub(1, Ordering::AcqRel);
+                }
+            }
+        });
+        Self {
+            tx,
+            last_used: Instant::now(),
+            pending,
+        }
+    }
+
+    fn send(&mut self, delivery: Delivery) -> Result<(), TrySendError<Delivery>> {
+        self.last_used = Instant::now();
+        // Counted before it is queued, so the worker never sees it done before it is counted
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        self.tx.try_send(delivery).inspect_err(|_| {
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+        })
+    }
+}
+
+/// Stop the worker of every url without event for `idle`, a worker that still has anything to
+/// post keeps going so a new event for its url can't be posted along or ahead of the old ones
+fn retire_idle(workers: &mut HashMap<String, Worker>, idle: Duration) {
+    workers.retain(|_, worker| {
+        worker.last_used.elapsed() < idle || worker.pending.load(Ordering::Acquire) > 0
+    });
+}
+
+/// Hand every event to the thread of each of its urls, url that is down only holds up its own events
+fn dispatch(rx: mpsc::Receiver<(JobEvent, Vec<String>)>, log: Arc<RwLock<DeliveryLog>>) {
+    info!("Run webhook thread");
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_millis(settings().webhook_timeout_ms))
+        .build()
+        .unwrap_or_else(|err| panic!("Webhook http client ({err})"));
+
+    let mut workers: HashMap<String, Worker> = HashMap::new();
+    loop {
+        let queued = rx.recv_timeout(WORKER_IDLE);
+        retire_idle(&mut workers, WORKER_IDLE);
+        let (event, urls) = match queued {
+            Ok(queued) => queued,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => Arc::new(body),
+            Err(err) => {
+                error!(
+                    "Serialize webhook event of job {} failed ({err})",
+                    event.job_id
+                );
+                continue;
+            }
+        };
+        for url in urls {
+            let worker = workers
+                .entry(url.clone())
+                .or_insert_with(|| Worker::spawn(&url, client.clone(), log.clone()));
+            let delivery = Delivery {
+                event: event.clone(),
+                body: body.clone(),
+            };
+            match worker.send(delivery) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => error!(
+                    "Webhook {url} queue is full, event of job {} dropped",
+                    event.job_id
+                ),
+                Err(TrySendError::Disconnected(_)) => {
+                    error!(
+                        "Webhook {url} thread is gone, event of job {} dropped",
+                        event.job_id
+                    );
+                    workers.remove(&url);
+                }
+            }
+        }
+    }
+}
+
+/// Delay before the next retry, doubled up to `max_delay`
+fn next_delay(delay: Duration, max_delay: Duration) -> Duration {
+    delay.saturating_mul(2).min(max_delay)
+}
+
+/// Post until the url answers 2xx or `webhook_max_attempts` is reached, delay is doubled every retry
+fn deliver(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    body: &[u8],
+    log: &RwLock<DeliveryLog>,
+    delivery_id: u64,
+) {
+    let max_attempts = settings().webhook_max_attempts;
+    let max_delay = Duration::from_millis(settings().webhook_retry_max_ms);
+    let mut delay = Duration::from_millis(settings().webhook_retry_ms).min(max_delay);
+    for attempt in 1..=max_attempts {
+        let mut request = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+        if let Some(secret) = &settings().webhook_secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, body));
+        }
+
+        let (response_code, error) = match request.send() {
+            Ok(response) if response.status().is_success() => (Some(response.status()), None),
+            Ok(response) => (
+                Some(response.status()),
+                Some(format!("answered {}", response.status())),
+            ),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        let delivered = error.is_none();
+        log.write().unwrap().update(delivery_id, |entry| {
+            entry.attempts = attempt;
+            entry.delivered = delivered;
+            entry.response_code = response_code.map(|code| code.as_u16());
+            entry.error = error;
+            entry.last_attempt_at = Some(now_ms());
+        });
+        if delivered {
+            debug!("Webhook {url} delivered on attempt {attempt}");
+            return;
+        }
+        if attempt == max_attempts {
+            break;
+        }
+        warn!("Webhook {url} attempt {attempt} failed, retry in {delay:?}");
+        thread::sleep(delay);
+        delay = next_delay(delay, max_delay);
+    }
+    error!("Webhook {url} gave up after {max_attempts} attempts");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Receiver stand-in answering each request with the next status, sends back every body
+    fn receiver(statuses: &'static [u16]) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let response = format!(
+                    "HTTP/1.1 {status} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                );
+                (&stream).write_all(response.as_bytes()).unwrap();
+                tx.send(String::from_utf8(body).unwrap()).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn event() -> JobEvent {
+        JobEvent {
+            job_id: JobId::new_v4(),
+            device_id: String::from("musang"),
+            status: JobStatus::Success,
+            failure_reason: None,
+            image_hash: None,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_deliver_retries_until_success() {
+        let (url, rx) = receiver(&[500, 200]);
+        let event = event();
+        let body = serde_json::to_vec(&event).unwrap();
+        let log = RwLock::new(DeliveryLog::default());
+        let delivery_id = log.write().unwrap().start(&event, &url);
+
+        deliver(
+            &reqwest::blocking::Client::new(),
+            &url,
+            &body,
+            &log,
+            delivery_id,
+        );
+
+        let received: serde_json::Value = serde_json::from_str(&rx.recv().unwrap()).unwrap();
+        assert_eq!(received["device_id"], "musang");
+        assert_eq!(received["status"], "success");
+        let entry = log.read().unwrap().entries[0].clone();
+        assert!(entry.delivered);
+        assert_eq!(entry.attempts, 2);
+        assert_eq!(entry.response_code, Some(200));
+    }
+
+    #[test]
+    fn test_dead_url_does_not_hold_up_others() {
+        let (url, received) = receiver(&[200, 200]);
+        let (tx, rx) = mpsc::sync_channel(8);
+        let log = Arc::new(RwLock::new(DeliveryLog::default()));
+        thread::spawn({
+            let log = log.clone();
+            move || dispatch(rx, log)
+        });
+
+        // Refused on every attempt, retried for seconds
+        let dead = String::from("http://127.0.0.1:1/hook");
+        tx.send((event(), vec![dead, url.clone()])).unwrap();
+        tx.send((event(), vec![url])).unwrap();
+        for _ in 0..2 {
+            received.recv_timeout(Duration::from_millis(500)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_busy_worker_is_not_retired() {
+        let client = reqwest::blocking::Client::new();
+        let log = Arc::new(RwLock::new(DeliveryLog::default()));
+        let dead = "http://127.0.0.1:1/hook";
+        let mut workers = HashMap::new();
+        workers.insert(
+            String::from(dead),
+            Worker::spawn(dead, client.clone(), log.clone()),
+        );
+        workers.insert(
+            String::from("http://a/hook"),
+            Worker::spawn("http://a/hook", client, log),
+        );
+
+        // Refused on every attempt, still retrying when the worker is idle for long enough
+        let delivery = Delivery {
+            event: event(),
+            body: Arc::new(Vec::new()),
+        };
+        assert!(workers.get_mut(dead).unwrap().send(delivery).is_ok());
+        retire_idle(&mut workers, Duration::ZERO);
+        assert_eq!(workers.keys().collect::<Vec<_>>(), [dead]);
+    }
+
+    #[test]
+    fn test_next_delay_is_capped() {
+        let max_delay = Duration::from_secs(60);
+        assert_eq!(
+            next_delay(Duration::from_secs(1), max_delay),
+            Duration::from_secs(2)
+        );
+        assert_eq!(next_delay(Duration::from_secs(40), max_delay), max_delay);
+        assert_eq!(next_delay(Duration::MAX, max_delay), max_delay);
+    }
+
+    #[test]
+    fn test_validate_callback_url() {
+        let hosts = [String::from("dashboard.local")];
+        assert!(validate_callback_url("https://dashboard.local/rocky", &hosts).is_ok());
+        assert!(validate_callback_url("http://DASHBOARD.local:8080/rocky", &hosts).is_ok());
+        assert!(validate_callback_url("http://169.254.169.254/latest", &hosts).is_err());
+        assert!(validate_callback_url("http://dashboard.local.evil/rocky", &hosts).is_err());
+        assert!(validate_callback_url("ftp://dashboard.local/rocky", &hosts).is_err());
+        assert!(validate_callback_url("https://dashboard.local/rocky", &[]).is_err());
+    }
+
+    #[test]
+    fn test_log_is_bounded() {
+        let mut log = DeliveryLog::default();
+        for _ in 0..=LOG_CAPACITY {
+            log.start(&event(), "http://a/hook");
+        }
+        assert_eq!(log.entries.len(), LOG_CAPACITY);
+        assert_eq!(log.entries[0].delivery_id, 2);
+    }
+}
@@ -36,12 +36,12 @@ def on_message(client: mqtt.Client, userdata: Queue, msg: mqtt.MQTTMessage):
 
 def handle_command(topic: str, payload: bytes) -> Optional[tuple[str, bytes]]: 
     # Decode payload
-    data = cbor.loads(payload) # [job_id, command]
+    data = cbor.loads(payload) # [transfer_token, command, image_hash]
 
     response_command = None
     print("\n-----------------------")
     try:
-        print(f"JobId \t: {data[0]}")
+        print(f"Token \t: {data[0]}")
         print(f"Image Hash : {data[2]}")
         match data[1]:
             case CommandType.OTA_REQUEST.value: